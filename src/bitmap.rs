@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 use crate::{block_cache::get_block_cache, block_dev::BlockDevice, BLOCK_SZ};
 
@@ -7,14 +7,35 @@ const BLOCK_BITS: usize = BLOCK_SZ * 8;
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
+    // 轮转分配时下一次开始查找的位置，为 None 时总是分配编号最小的空闲位
+    cursor: Option<usize>,
 }
 impl Bitmap {
     pub fn new(start_block_id: usize, blocks: usize) -> Self {
         Self {
             start_block_id,
             blocks,
+            cursor: None,
         }
     }
+
+    /// Resume each allocation from where the last one stopped, wrapping around,
+    /// to spread writes over the whole device instead of its low-numbered blocks
+    pub fn set_rotating(&mut self, rotating: bool) {
+        self.cursor = rotating.then_some(0);
+    }
+
+    /// Restart the scan of rotating allocation from the first bit
+    pub fn rewind(&mut self) {
+        if let Some(cursor) = self.cursor.as_mut() {
+            *cursor = 0;
+        }
+    }
+
+    /// Whether allocations are rotating
+    pub fn is_rotating(&self) -> bool {
+        self.cursor.is_some()
+    }
 }
 // 512字节
 type BitmapBlock = [u64; 64];
@@ -28,28 +49,52 @@ fn decomposition(mut bit: usize) -> (usize, usize, usize) {
 
 impl Bitmap {
     /// Allocate a new block from a block device
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
-                {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    // 位图中分配出的bit的编号
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
+    pub fn alloc(&mut self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+        let start = self.cursor.unwrap_or(0);
+        let bit = self
+            .alloc_in(block_device, start, self.maximum())
+            .or_else(|| self.alloc_in(block_device, 0, start))?;
+        let maximum = self.maximum();
+        if let Some(cursor) = self.cursor.as_mut() {
+            *cursor = (bit + 1) % maximum;
+        }
+        Some(bit)
+    }
+
+    /// Allocate the first free bit in `from..to`
+    fn alloc_in(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        from: usize,
+        to: usize,
+    ) -> Option<usize> {
+        for block_id in from / BLOCK_BITS..to.div_ceil(BLOCK_BITS) {
+            let pos = get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .modify(0, |bitmap_block: &mut BitmapBlock| {
+                    for (bits64_pos, bits64) in bitmap_block.iter_mut().enumerate() {
+                        let base = block_id * BLOCK_BITS + bits64_pos * 64;
+                        if base + 64 <= from || base >= to {
+                            continue;
+                        }
+                        // 屏蔽掉 from..to 范围之外的位
+                        let mut free = !*bits64;
+                        if from > base {
+                            free &= u64::MAX << (from - base);
+                        }
+                        if to < base + 64 {
+                            free &= u64::MAX >> (base + 64 - to);
+                        }
+                        if free != 0 {
+                            let inner_pos = free.trailing_zeros() as usize;
+                            // modify cache
+                            *bits64 |= 1u64 << inner_pos;
+                            // 位图中分配出的bit的编号
+                            return Some(base + inner_pos);
+                        }
+                    }
                     None
-                }
-            });
+                });
             if pos.is_some() {
                 return pos;
             }
@@ -57,14 +102,98 @@ impl Bitmap {
         None
     }
 
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+    /// Id of the bitmap block holding a bit
+    pub fn block_of(&self, bit: usize) -> usize {
+        self.start_block_id + bit / BLOCK_BITS
+    }
+
+    /// Allocate a given bit, return false if it is out of range or already allocated
+    pub fn alloc_at(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        bit < self.maximum() && self.alloc_in(block_device, bit, bit + 1).is_some()
+    }
+
+    /// Release an allocated bit, return false if it is out of range or not allocated
+    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        if bit >= self.maximum() {
+            return false;
+        }
         let (block_pos, bit64_pos, inner_pos) = decomposition(bit);
         get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
             .lock()
             .modify(0, |bitmap_block: &mut BitmapBlock| {
-                assert!(bitmap_block[bit64_pos] & (1u64 << inner_pos) > 0);
+                if bitmap_block[bit64_pos] & (1u64 << inner_pos) == 0 {
+                    return false;
+                }
                 bitmap_block[bit64_pos] -= 1u64 << inner_pos;
-            });
+                true
+            })
+    }
+
+    /// Whether a bit is allocated, false if it is out of range
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        if bit >= self.maximum() {
+            return false;
+        }
+        let (block_pos, bit64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bit64_pos] & (1u64 << inner_pos) != 0
+            })
+    }
+
+    /// Count the allocated bits
+    pub fn allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        bitmap_block
+                            .iter()
+                            .map(|bits64| bits64.count_ones() as usize)
+                            .sum::<usize>()
+                    })
+            })
+            .sum()
+    }
+
+    /// Runs of consecutive free bits as `(start, length)` in ascending order,
+    /// a run may span several words and bitmap blocks
+    pub fn free_ranges(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        // 正在统计的空闲段的起点
+        let mut run_start: Option<usize> = None;
+        for block_id in 0..self.blocks {
+            get_block_cache(block_id + self.start_block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    for (bits64_pos, &bits64) in bitmap_block.iter().enumerate() {
+                        // 整个 u64 都已分配或都空闲时，空闲段的状态不变
+                        if (run_start.is_none() && bits64 == u64::MAX)
+                            || (run_start.is_some() && bits64 == 0)
+                        {
+                            continue;
+                        }
+                        let base = block_id * BLOCK_BITS + bits64_pos * 64;
+                        for inner_pos in 0..64 {
+                            let free = bits64 & (1u64 << inner_pos) == 0;
+                            match (free, run_start) {
+                                (true, None) => run_start = Some(base + inner_pos),
+                                (false, Some(start)) => {
+                                    ranges.push((start, base + inner_pos - start));
+                                    run_start = None;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                });
+        }
+        if let Some(start) = run_start {
+            ranges.push((start, self.maximum() - start));
+        }
+        ranges
     }
 
     /// Get the max number of allocatable blocks
@@ -1,26 +1,217 @@
-use alloc::sync::Arc;
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use spin::Mutex;
 
-use crate::{block_dev::BlockDevice, BLOCK_SZ};
+use crate::{
+    block_dev::BlockDevice,
+    error::EfsError,
+    trace::{trace, TraceEvent},
+    BLOCK_SZ,
+};
+
+/// A fixed set of block buffers supplied by the caller, e.g. placed in a
+/// DMA-safe memory region, for block caches to use instead of the heap.
+/// Buffers go back to the pool when their block cache is dropped.
+pub struct BufferPool {
+    base: NonNull<[u8; BLOCK_SZ]>,
+    count: usize,
+    // 空闲缓冲区的下标
+    free: Mutex<Vec<usize>>,
+}
+
+// 每个缓冲区同时只被一个 CacheBuffer 使用
+unsafe impl Send for BufferPool {}
+unsafe impl Sync for BufferPool {}
+
+impl BufferPool {
+    pub fn new(buffers: &'static mut [[u8; BLOCK_SZ]]) -> Self {
+        let count = buffers.len();
+        Self {
+            base: NonNull::new(buffers.as_mut_ptr()).unwrap(),
+            count,
+            free: Mutex::new((0..count).rev().collect()),
+        }
+    }
+    /// Total number of buffers of the pool
+    pub fn capacity(&self) -> usize {
+        self.count
+    }
+    /// Number of buffers not used by a block cache
+    pub fn available(&self) -> usize {
+        self.free.lock().len()
+    }
+    fn take(&self) -> Option<NonNull<[u8; BLOCK_SZ]>> {
+        let idx = self.free.lock().pop()?;
+        Some(unsafe { NonNull::new_unchecked(self.base.as_ptr().add(idx)) })
+    }
+    fn give_back(&self, ptr: NonNull<[u8; BLOCK_SZ]>) {
+        let idx = unsafe { ptr.as_ptr().offset_from(self.base.as_ptr()) } as usize;
+        assert!(idx < self.count);
+        self.free.lock().push(idx);
+    }
+}
+
+// 块缓存的缓冲区，按块设备要求的对齐方式单独分配，
+// 以便直接交给需要 DMA 对齐的驱动程序。至少按 u64 对齐，
+// 磁盘上的数据结构放在块内对齐的偏移量处时都可以直接引用
+struct CacheBuffer {
+    ptr: NonNull<[u8; BLOCK_SZ]>,
+    origin: BufferOrigin,
+}
+
+// 缓冲区来自堆时记录分配时的布局，来自缓冲池时在释放时归还
+enum BufferOrigin {
+    Heap(Layout),
+    Pool(Arc<BufferPool>),
+}
+
+// 缓冲区由 CacheBuffer 独占，和 [u8; BLOCK_SZ] 一样可以跨线程传递
+unsafe impl Send for CacheBuffer {}
+unsafe impl Sync for CacheBuffer {}
+
+impl CacheBuffer {
+    fn new_zeroed(align: usize) -> Self {
+        let layout = Layout::from_size_align(BLOCK_SZ, align.max(core::mem::align_of::<u64>()))
+            .expect("Invalid block device alignment!");
+        let ptr = unsafe { alloc_zeroed(layout) } as *mut [u8; BLOCK_SZ];
+        match NonNull::new(ptr) {
+            Some(ptr) => Self {
+                ptr,
+                origin: BufferOrigin::Heap(layout),
+            },
+            None => handle_alloc_error(layout),
+        }
+    }
+
+    fn from_pool(pool: &Arc<BufferPool>, align: usize) -> Self {
+        let ptr = pool.take().expect("Run Out of pooled buffers!");
+        let mut buffer = Self {
+            ptr,
+            origin: BufferOrigin::Pool(Arc::clone(pool)),
+        };
+        assert!(
+            (ptr.as_ptr() as usize).is_multiple_of(align.max(core::mem::align_of::<u64>())),
+            "Pooled buffer is not aligned for the block device!"
+        );
+        buffer.fill(0);
+        buffer
+    }
+
+    fn alloc(pool: Option<&Arc<BufferPool>>, align: usize) -> Self {
+        match pool {
+            Some(pool) => Self::from_pool(pool, align),
+            None => Self::new_zeroed(align),
+        }
+    }
+}
+
+impl Deref for CacheBuffer {
+    type Target = [u8; BLOCK_SZ];
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl DerefMut for CacheBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl Drop for CacheBuffer {
+    fn drop(&mut self) {
+        match &self.origin {
+            BufferOrigin::Heap(layout) => unsafe { dealloc(self.ptr.as_ptr() as *mut u8, *layout) },
+            BufferOrigin::Pool(pool) => pool.give_back(self.ptr),
+        }
+    }
+}
 
 pub struct BlockCache {
-    cache: [u8; BLOCK_SZ],
+    cache: CacheBuffer,
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    // 与管理器共享的开关，管理器修改它们时不必给块缓存加锁
+    flags: Arc<CacheFlags>,
+}
+
+#[derive(Default)]
+struct CacheFlags {
+    // 写回后是否读回校验
+    verify: AtomicBool,
+    // modify 没有改变内容时是否保持干净
+    skip_unchanged: AtomicBool,
 }
 
 // 创建一个 BlockCache 的时候，
 // 这将触发一次 read_block 将一个块上的数据从磁盘读到缓冲区cache
 impl BlockCache {
     pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
-        let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        Self::load(block_id, block_device, None)
+    }
+
+    /// Same as `new`, but take the buffer from `pool` instead of the heap.
+    /// Panics if all buffers of the pool are in use.
+    pub fn new_pooled(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        pool: &Arc<BufferPool>,
+    ) -> Self {
+        Self::load(block_id, block_device, Some(pool))
+    }
+
+    /// Create a block cache without reading the block from the device,
+    /// for blocks whose whole content is about to be overwritten. It starts
+    /// out modified, so it is written back even if it stays all zeros.
+    pub fn new_zeroed(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+        Self::blank_with(block_id, block_device, None)
+    }
+
+    fn load(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        pool: Option<&Arc<BufferPool>>,
+    ) -> Self {
+        let mut block_cache = Self::zeroed_with(block_id, block_device, pool);
+        block_cache
+            .block_device
+            .read_block(block_id, &mut block_cache.cache[..]);
+        block_cache
+    }
+
+    // 没有读设备的全 0 缓存与磁盘上的内容无关，一开始就要标记为已修改，
+    // 否则跳过未改变的写入时，写入全 0 的块会被丢弃
+    fn blank_with(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        pool: Option<&Arc<BufferPool>>,
+    ) -> Self {
+        let mut block_cache = Self::zeroed_with(block_id, block_device, pool);
+        block_cache.modified = true;
+        block_cache
+    }
+
+    fn zeroed_with(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        pool: Option<&Arc<BufferPool>>,
+    ) -> Self {
         Self {
-            cache,
+            cache: CacheBuffer::alloc(pool, block_device.alignment()),
             block_id,
             block_device,
             modified: false,
+            flags: Arc::new(CacheFlags::default()),
         }
     }
 }
@@ -39,9 +230,8 @@ impl BlockCache {
     where
         T: Sized,
     {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
+        let addr = self.checked_addr::<T>(offset);
+        assert!(addr.is_multiple_of(core::mem::align_of::<T>()));
         unsafe { &*(addr as *const T) }
     }
     // get_ref 是一个泛型方法，它可以获取缓冲区中的位于
@@ -51,20 +241,93 @@ impl BlockCache {
     where
         T: Sized,
     {
-        let type_size = core::mem::size_of::<T>();
-        assert!(offset + type_size <= BLOCK_SZ);
+        let addr = self.checked_addr::<T>(offset);
+        assert!(addr.is_multiple_of(core::mem::align_of::<T>()));
         self.modified = true;
-        let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
+    /// Copy out a `T` at an offset which may not be aligned for it
+    pub fn read_unaligned<T: Copy>(&self, offset: usize) -> T {
+        let addr = self.checked_addr::<T>(offset);
+        unsafe { core::ptr::read_unaligned(addr as *const T) }
+    }
+    /// Store a `T` at an offset which may not be aligned for it
+    pub fn write_unaligned<T: Copy>(&mut self, offset: usize, value: T) {
+        let addr = self.checked_addr::<T>(offset);
+        self.modified = true;
+        unsafe { core::ptr::write_unaligned(addr as *mut T, value) }
+    }
+    // 检查 T 位于块内，返回其地址
+    fn checked_addr<T>(&self, offset: usize) -> usize {
+        let type_size = core::mem::size_of::<T>();
+        assert!(offset
+            .checked_add(type_size)
+            .is_some_and(|end| end <= BLOCK_SZ));
+        self.addr_of_offset(offset)
+    }
+    /// View the whole block as a slice of `T`, e.g. a directory block as `[DirEntry]`
+    pub fn get_array_ref<T>(&self) -> &[T] {
+        let addr = self.array_addr::<T>();
+        let len = BLOCK_SZ / core::mem::size_of::<T>();
+        unsafe { core::slice::from_raw_parts(addr as *const T, len) }
+    }
+    /// Mutable version of `get_array_ref`, marks the block as modified
+    pub fn get_array_mut<T>(&mut self) -> &mut [T] {
+        let addr = self.array_addr::<T>();
+        let len = BLOCK_SZ / core::mem::size_of::<T>();
+        self.modified = true;
+        unsafe { core::slice::from_raw_parts_mut(addr as *mut T, len) }
+    }
+    fn array_addr<T>(&self) -> usize {
+        // 块必须恰好容纳整数个 T，在编译期检查
+        const {
+            assert!(core::mem::size_of::<T>() > 0);
+            assert!(BLOCK_SZ.is_multiple_of(core::mem::size_of::<T>()));
+        }
+        let addr = self.addr_of_offset(0);
+        assert!(addr.is_multiple_of(core::mem::align_of::<T>()));
+        addr
+    }
 }
 
 impl BlockCache {
-    fn sync(&mut self) {
-        if self.modified {
-            self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
+    /// Write back the block if it is modified, return whether it is written
+    fn sync(&mut self) -> bool {
+        self.try_sync().unwrap_or(true)
+    }
+
+    /// Same as `sync`, but if write verification is on, read the block back
+    /// and fail if it differs. A block failing verification stays modified.
+    fn try_sync(&mut self) -> Result<bool, EfsError> {
+        if !self.modified {
+            return Ok(false);
+        }
+        self.modified = false;
+        self.block_device
+            .write_block(self.block_id, &self.cache[..]);
+        if self.flags.verify.load(Ordering::Relaxed) {
+            let mut written = CacheBuffer::new_zeroed(self.block_device.alignment());
+            self.block_device
+                .read_block(self.block_id, &mut written[..]);
+            if *written != *self.cache {
+                self.modified = true;
+                return Err(EfsError::WriteVerifyFailed);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl BlockCache {
+    // 不做写后校验，校验需要分配缓冲区
+    fn write_back(&mut self) -> bool {
+        if !self.modified {
+            return false;
         }
+        self.modified = false;
+        self.block_device
+            .write_block(self.block_id, &self.cache[..]);
+        true
     }
 }
 
@@ -74,12 +337,79 @@ impl Drop for BlockCache {
     }
 }
 
+// 调试构建中记录当前线程正在 read/modify 的块。闭包里再次给同一个块缓存加锁
+// 会在自旋锁上永远等待，改为在获取它时 panic。no_std 下没有线程局部变量，不做检查
+#[cfg(all(feature = "std", debug_assertions))]
+std::thread_local! {
+    static HELD_BLOCKS: core::cell::RefCell<Vec<CacheKey>> = const {
+        core::cell::RefCell::new(Vec::new())
+    };
+}
+
+/// Marks a block as locked by the current thread while a `read` or `modify`
+/// closure runs, see `assert_not_held`
+struct HeldBlock {
+    #[cfg(all(feature = "std", debug_assertions))]
+    key: CacheKey,
+}
+
+impl HeldBlock {
+    fn enter(_key: CacheKey) -> Self {
+        #[cfg(all(feature = "std", debug_assertions))]
+        {
+            HELD_BLOCKS.with(|held| held.borrow_mut().push(_key));
+            Self { key: _key }
+        }
+        #[cfg(not(all(feature = "std", debug_assertions)))]
+        Self {}
+    }
+}
+
+#[cfg(all(feature = "std", debug_assertions))]
+impl Drop for HeldBlock {
+    fn drop(&mut self) {
+        HELD_BLOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            let pos = held.iter().rposition(|key| *key == self.key).unwrap();
+            held.remove(pos);
+        });
+    }
+}
+
+/// Panic if the current thread is inside a `read` or `modify` of block `key`,
+/// locking its cache again would deadlock. Only checked in debug builds with `std`.
+fn assert_not_held(_key: CacheKey) {
+    #[cfg(all(feature = "std", debug_assertions))]
+    {
+        let held = HELD_BLOCKS.with(|held| held.borrow().contains(&_key));
+        assert!(
+            !held,
+            "block {} is already locked by this thread in BlockCache::read or modify, \
+             locking it again would deadlock",
+            _key.1
+        );
+    }
+}
+
 impl BlockCache {
     pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+        let _held = HeldBlock::enter(cache_key(self.block_id, &self.block_device));
         f(self.get_ref(offset))
     }
     pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
-        f(self.get_mut(offset))
+        let _held = HeldBlock::enter(cache_key(self.block_id, &self.block_device));
+        if !self.flags.skip_unchanged.load(Ordering::Relaxed) || self.modified {
+            return f(self.get_mut(offset));
+        }
+        // 比较修改前后的内容，没有变化时不必写回
+        let range = offset..offset + core::mem::size_of::<T>();
+        let mut before = [0u8; BLOCK_SZ];
+        before[..range.len()].copy_from_slice(&self.cache[range.clone()]);
+        let result = f(self.get_mut(offset));
+        if before[..range.len()] == self.cache[range] {
+            self.modified = false;
+        }
+        result
     }
 }
 
@@ -93,52 +423,477 @@ pub struct BlockCacheManager {
     // 让它可以对块缓存进行访问
     // 互斥访问在单核上的意义在于提供内部可变性通过编译，
     // 在多核环境下则可以帮助我们避免可能的并发冲突
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<CacheEntry>,
+    // 同时驻留在内存中的块缓存数量上限
+    capacity: usize,
+    policy: EvictionPolicy,
+    // 需要写后校验的设备，以及其中需要校验的块号上界
+    verify_writes: Vec<(usize, usize)>,
+    // 单独限制了驻留块缓存数量的设备，以及其上限
+    device_capacity: Vec<(usize, usize)>,
+    // 逻辑时钟，每次查找块缓存时加一
+    clock: usize,
+    // 超过这么多次查找都未被访问的干净块缓存会被提前替换
+    ttl: Option<usize>,
+    // 新的块缓存是否跳过没有改变内容的修改
+    skip_unchanged: bool,
+    // 新的块缓存从这里取缓冲区，为 None 时在堆上分配
+    pool: Option<Arc<BufferPool>>,
+    // 被替换时写回的修改过的块缓存数
+    evict_flushes: usize,
+}
+
+struct CacheEntry {
+    key: CacheKey,
+    cache: Arc<Mutex<BlockCache>>,
+    flags: Arc<CacheFlags>,
+    // 被访问的次数，供 LFU 策略使用
+    frequency: u32,
+    // 最近一次被访问时的逻辑时钟
+    last_access: usize,
+}
+
+/// How to choose the block cache to replace when the cache is full.
+/// Only caches that are not used outside of the manager can be replaced.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvictionPolicy {
+    /// Replace the block cache loaded first
+    Fifo,
+    /// Replace the block cache accessed least recently
+    Lru,
+    /// Replace the block cache accessed least frequently.
+    /// Frequencies are halved at each replacement so that
+    /// blocks which were hot long ago don't stay forever.
+    Lfu,
+}
+
+/// A block cache is identified by its block device and its block id,
+/// so that several devices can share the cache without aliasing
+type CacheKey = (usize, usize);
+
+fn cache_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> CacheKey {
+    (device_key(block_device), block_id)
+}
+
+pub(crate) fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
 }
 
+/// Approximate bytes held by one resident cache entry: the queue slot,
+/// the Arc counters, the `Mutex<BlockCache>` and the block buffer it points to
+const BLOCK_CACHE_ENTRY_SZ: usize = core::mem::size_of::<CacheEntry>()
+    + 4 * core::mem::size_of::<usize>()
+    + core::mem::size_of::<Mutex<BlockCache>>()
+    + core::mem::size_of::<CacheFlags>()
+    // 缓冲区单独分配在堆上，对齐时最多浪费 align_of::<u64>() 字节
+    + BLOCK_SZ
+    + core::mem::align_of::<u64>();
+
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
             queue: VecDeque::new(),
+            capacity: BLOCK_CACHE_SIZE,
+            policy: EvictionPolicy::Fifo,
+            verify_writes: Vec::new(),
+            device_capacity: Vec::new(),
+            clock: 0,
+            ttl: None,
+            skip_unchanged: false,
+            pool: None,
+            evict_flushes: 0,
         }
     }
 }
 
+impl Default for BlockCacheManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCacheManager {
+    /// Get the max number of resident block caches
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Set the max number of resident block caches,
+    /// evicting unused caches if there are too many of them
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+        while self.queue.len() > self.capacity && self.evict_one() {}
+    }
+
+    /// Limit the block caches of one device to `capacity` on top of the overall
+    /// capacity, or lift the limit if None. When a device reaches its limit, its
+    /// own caches are evicted to make room, leaving other devices' caches alone.
+    pub fn set_device_capacity(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        capacity: Option<usize>,
+    ) {
+        let device = device_key(block_device);
+        self.device_capacity.retain(|(key, _)| *key != device);
+        if let Some(capacity) = capacity {
+            assert!(capacity > 0);
+            self.device_capacity.push((device, capacity));
+            while self.resident_of(device) > capacity
+                && self.evict_one_where(|entry| entry.key.0 == device)
+            {}
+        }
+    }
+
+    /// Get the max number of resident block caches of a device
+    pub fn device_capacity(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let device = device_key(block_device);
+        self.device_capacity
+            .iter()
+            .find(|(key, _)| *key == device)
+            .map_or(self.capacity, |(_, capacity)| {
+                (*capacity).min(self.capacity)
+            })
+    }
+
+    fn resident_of(&self, device: usize) -> usize {
+        self.queue
+            .iter()
+            .filter(|entry| entry.key.0 == device)
+            .count()
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Read back and compare the blocks of a device below `end_block_id`
+    /// each time they are written, or stop doing so if `end_block_id` is None
+    pub fn set_verify_writes(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        end_block_id: Option<usize>,
+    ) {
+        let device = device_key(block_device);
+        self.verify_writes.retain(|(key, _)| *key != device);
+        if let Some(end_block_id) = end_block_id {
+            self.verify_writes.push((device, end_block_id));
+        }
+        for entry in self.queue.iter().filter(|entry| entry.key.0 == device) {
+            let verify = self.should_verify(entry.key);
+            entry.flags.verify.store(verify, Ordering::Relaxed);
+        }
+    }
+
+    /// Compare the bytes a `modify` covers before and after it, and keep a clean block
+    /// clean if they are unchanged, so that rewriting identical data doesn't write the
+    /// device again. Saves wear on flash at the cost of a copy per modification.
+    pub fn set_skip_unchanged_writes(&mut self, skip: bool) {
+        self.skip_unchanged = skip;
+        for entry in self.queue.iter() {
+            entry.flags.skip_unchanged.store(skip, Ordering::Relaxed);
+        }
+    }
+
+    fn should_verify(&self, key: CacheKey) -> bool {
+        self.verify_writes
+            .iter()
+            .any(|(device, end_block_id)| *device == key.0 && key.1 < *end_block_id)
+    }
+
+    /// Approximate bytes held by all resident block caches
+    pub fn memory_usage(&self) -> usize {
+        self.queue.len() * BLOCK_CACHE_ENTRY_SZ
+    }
+
+    /// Derive the capacity from a memory budget in bytes.
+    /// At least one block cache is always allowed.
+    pub fn set_memory_budget(&mut self, bytes: usize) {
+        self.set_capacity((bytes / BLOCK_CACHE_ENTRY_SZ).max(1));
+    }
+
+    /// Let clean block caches which haven't been accessed in the last `ttl`
+    /// lookups be dropped even if the cache is not full, or never if None
+    pub fn set_cache_ttl(&mut self, ttl: Option<usize>) {
+        self.ttl = ttl;
+        self.expire();
+    }
+
+    /// Take the buffers of block caches loaded from now on from `pool`, or
+    /// from the heap if None. The capacity should not exceed the buffers of
+    /// the pool, running out of them panics.
+    pub fn set_buffer_pool(&mut self, pool: Option<Arc<BufferPool>>) {
+        self.pool = pool;
+    }
+
+    /// Number of modified block caches written back when they were evicted
+    pub fn evict_flushes(&self) -> usize {
+        self.evict_flushes
+    }
+
+    /// Whether a block of a device is resident in the cache
+    pub fn is_resident(&self, block_id: usize, block_device: &Arc<dyn BlockDevice>) -> bool {
+        let key = cache_key(block_id, block_device);
+        self.queue.iter().any(|entry| entry.key == key)
+    }
+
+    // 丢弃过期的干净块缓存。只查看不被别处持有的块缓存，不需要加锁
+    fn expire(&mut self) {
+        if let Some(ttl) = self.ttl {
+            let clock = self.clock;
+            self.queue.retain_mut(|entry| {
+                clock - entry.last_access <= ttl
+                    || Arc::get_mut(&mut entry.cache).is_none_or(|cache| cache.get_mut().modified)
+            });
+        }
+    }
+
+    /// Drop a block cache that is not used outside of the manager,
+    /// chosen according to the eviction policy
+    fn evict_one(&mut self) -> bool {
+        self.evict_one_where(|_| true)
+    }
+
+    /// Same as `evict_one`, but only among the entries `filter` accepts.
+    /// A modified cache failing write verification is kept and the next one is tried.
+    fn evict_one_where(&mut self, filter: impl Fn(&CacheEntry) -> bool) -> bool {
+        let unused = |entry: &CacheEntry| Arc::strong_count(&entry.cache) == 1 && filter(entry);
+        match self.policy {
+            // 队列按载入（FIFO）或最近访问（LRU）的先后排列，从队头开始替换
+            EvictionPolicy::Fifo | EvictionPolicy::Lru => {
+                for idx in 0..self.queue.len() {
+                    if unused(&self.queue[idx]) && self.try_evict_at(idx) {
+                        return true;
+                    }
+                }
+            }
+            EvictionPolicy::Lfu => {
+                let mut candidates: Vec<usize> = (0..self.queue.len())
+                    .filter(|&idx| unused(&self.queue[idx]))
+                    .collect();
+                candidates.sort_by_key(|&idx| self.queue[idx].frequency);
+                for idx in candidates {
+                    if self.try_evict_at(idx) {
+                        for entry in self.queue.iter_mut() {
+                            entry.frequency >>= 1;
+                        }
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // 写回并丢弃一个不被别处持有的块缓存。写回校验失败时块仍是脏的，留在队列中
+    fn try_evict_at(&mut self, idx: usize) -> bool {
+        let entry = &mut self.queue[idx];
+        let Some(cache) = Arc::get_mut(&mut entry.cache) else {
+            return false;
+        };
+        // 在丢弃之前显式写回，缓冲区归还缓冲池之前数据已经到达设备
+        let Ok(written) = cache.get_mut().try_sync() else {
+            return false;
+        };
+        let block = entry.key.1;
+        self.queue.remove(idx);
+        trace(|| TraceEvent::Evict { block });
+        if written {
+            self.evict_flushes += 1;
+        }
+        true
+    }
+}
+
 impl BlockCacheManager {
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
+        let key = cache_key(block_id, &block_device);
+        assert_not_held(key);
+        let pool = self.pool.clone();
+        self.get_or_insert(key, || {
+            BlockCache::load(block_id, block_device, pool.as_ref())
+        })
+    }
+
+    /// Same as `get_block_cache`, but a block that is not resident yet
+    /// is not read from the device and starts out zeroed and modified
+    pub fn get_block_cache_zeroed(
+        &mut self,
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Arc<Mutex<BlockCache>> {
+        let key = cache_key(block_id, &block_device);
+        assert_not_held(key);
+        let pool = self.pool.clone();
+        self.get_or_insert(key, || {
+            BlockCache::blank_with(block_id, block_device, pool.as_ref())
+        })
+    }
+
+    /// Load the blocks not resident yet into clean caches, reading each run of
+    /// consecutive blocks with one `read_blocks` request
+    pub fn prefetch(&mut self, block_ids: &[usize], block_device: &Arc<dyn BlockDevice>) {
+        let missing: Vec<usize> = block_ids
+            .iter()
+            .copied()
+            .filter(|&block_id| !self.is_resident(block_id, block_device))
+            .collect();
+        let mut start = 0;
+        while start < missing.len() {
+            // 找出从 start 开始编号连续的一段
+            let mut end = start + 1;
+            while end < missing.len() && missing[end] == missing[end - 1] + 1 {
+                end += 1;
+            }
+            let mut buf = vec![0u8; (end - start) * BLOCK_SZ];
+            block_device.read_blocks(missing[start], &mut buf);
+            for (i, data) in buf.chunks_exact(BLOCK_SZ).enumerate() {
+                let block_id = missing[start + i];
+                let key = cache_key(block_id, block_device);
+                let pool = self.pool.clone();
+                self.get_or_insert(key, || {
+                    let mut block_cache =
+                        BlockCache::zeroed_with(block_id, Arc::clone(block_device), pool.as_ref());
+                    block_cache.cache.copy_from_slice(data);
+                    block_cache
+                });
+            }
+            start = end;
+        }
+    }
+
+    fn get_or_insert(
+        &mut self,
+        key: CacheKey,
+        new_cache: impl FnOnce() -> BlockCache,
+    ) -> Arc<Mutex<BlockCache>> {
+        self.clock += 1;
+        self.expire();
+        let clock = self.clock;
+        if let Some(idx) = self.queue.iter().position(|entry| entry.key == key) {
+            self.queue[idx].last_access = clock;
+            match self.policy {
+                EvictionPolicy::Fifo => {}
+                EvictionPolicy::Lru => {
+                    let entry = self.queue.remove(idx).unwrap();
+                    self.queue.push_back(entry);
+                    return Arc::clone(&self.queue.back().unwrap().cache);
+                }
+                EvictionPolicy::Lfu => {
+                    self.queue[idx].frequency = self.queue[idx].frequency.saturating_add(1);
+                }
+            }
+            Arc::clone(&self.queue[idx].cache)
         } else {
-            if self.queue.len() == BLOCK_CACHE_SIZE {
+            // 设备达到自己的上限时只替换它自己的块缓存
+            let device_full = self
+                .device_capacity
+                .iter()
+                .find(|(device, _)| *device == key.0)
+                .is_some_and(|(device, capacity)| self.resident_of(*device) >= *capacity);
+            if device_full {
+                if !self.evict_one_where(|entry| entry.key.0 == key.0) {
+                    panic!("Run Out of BlockCache!");
+                }
+            } else if self.queue.len() >= self.capacity {
                 /*
                 此时队头对应的块缓存可能仍在使用：判断的标志是其强引用计数 ≥ 2 ，即
                 除了块缓存管理器保留的一份副本之外，在外面还有若干份副本正在使用。
                 因此，我们的做法是从队头遍历到队尾找到第一个强引用计数恰好为 1
                 的块缓存并将其替换出去。
                                 */
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
+                if !self.evict_one() {
                     panic!("Run Out of BlockCache!");
                 }
             }
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id, Arc::clone(&block_device))));
-            self.queue.push_back((block_id,Arc::clone(&block_cache)));
+            let block_cache = new_cache();
+            let flags = Arc::clone(&block_cache.flags);
+            flags
+                .verify
+                .store(self.should_verify(key), Ordering::Relaxed);
+            flags
+                .skip_unchanged
+                .store(self.skip_unchanged, Ordering::Relaxed);
+            let block_cache = Arc::new(Mutex::new(block_cache));
+            self.queue.push_back(CacheEntry {
+                key,
+                cache: Arc::clone(&block_cache),
+                flags,
+                frequency: 1,
+                last_access: clock,
+            });
             block_cache
         }
     }
+
+    /// Copy out the clean block caches of a device, so that they can be
+    /// imported again after the same device is remounted.
+    /// Caches locked by someone else at the moment are skipped.
+    pub fn export_warm(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<(usize, [u8; BLOCK_SZ])> {
+        let device = device_key(block_device);
+        self.queue
+            .iter()
+            .filter(|entry| entry.key.0 == device)
+            .filter_map(|entry| {
+                let cache = entry.cache.try_lock()?;
+                (!cache.modified).then(|| (entry.key.1, *cache.cache))
+            })
+            .collect()
+    }
+
+    /// Load block caches exported by `export_warm` for the same device without reading it.
+    /// Blocks already resident are skipped, and importing stops when the cache is full.
+    pub fn import_warm(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+        entries: Vec<(usize, [u8; BLOCK_SZ])>,
+    ) {
+        for (block_id, data) in entries {
+            let key = cache_key(block_id, block_device);
+            if self.queue.iter().any(|entry| entry.key == key) {
+                continue;
+            }
+            if self.queue.len() >= self.capacity && !self.evict_one() {
+                break;
+            }
+            let mut block_cache =
+                BlockCache::zeroed_with(block_id, Arc::clone(block_device), self.pool.as_ref());
+            *block_cache.cache = data;
+            self.get_or_insert(key, || block_cache);
+        }
+    }
+
+    /// Drop all block caches of a device that are not used outside of the manager,
+    /// they are written back when dropped.
+    /// Return the caches of the device which are still in use.
+    fn invalidate_device(
+        &mut self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<Arc<Mutex<BlockCache>>> {
+        let device = device_key(block_device);
+        self.queue
+            .retain(|entry| entry.key.0 != device || Arc::strong_count(&entry.cache) > 1);
+        self.queue
+            .iter()
+            .filter(|entry| entry.key.0 == device)
+            .map(|entry| Arc::clone(&entry.cache))
+            .collect()
+    }
 }
 
+/*
+加锁顺序：持有块缓存的锁时可以再获取管理器的锁（例如在 modify 的闭包里查找另一个块），
+反过来持有管理器的锁时绝不能等待块缓存的锁，只能复制出块缓存的引用、释放管理器的锁后再加锁。
+管理器内部只会查看不被别处持有的块缓存，或者用 try_lock 跳过正在使用的块缓存。
+*/
 use lazy_static::*;
 lazy_static! {
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
@@ -154,10 +909,171 @@ pub fn get_block_cache(
         .get_block_cache(block_id, block_device)
 }
 
+/// Load the given blocks into the block cache ahead of their use
+pub fn prefetch_blocks(block_ids: &[usize], block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER.lock().prefetch(block_ids, block_device);
+}
+
+/// Get a block cache without reading the block if it is not resident yet
+pub fn get_block_cache_zeroed(
+    block_id: usize,
+    block_device: Arc<dyn BlockDevice>,
+) -> Arc<Mutex<BlockCache>> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache_zeroed(block_id, block_device)
+}
+
+/// Flush and forget the block caches of a device that is going away.
+/// Caches still in use somewhere are flushed but stay resident,
+/// return the number of them so the caller knows the device is still referenced.
+pub fn invalidate_device_cache(block_device: &Arc<dyn BlockDevice>) -> usize {
+    let in_use = BLOCK_CACHE_MANAGER.lock().invalidate_device(block_device);
+    for cache in in_use.iter() {
+        cache.lock().sync();
+    }
+    in_use.len()
+}
+
+// 格式化时每次写入设备的清零块数
+const ZERO_BATCH_BLOCKS: usize = 64;
+
+/// Zero `count` blocks from `start_block` with batched `write_blocks` calls,
+/// bypassing the block cache. Caches of the device are flushed and forgotten
+/// first, so none of them writes old content back over the zeroed blocks later.
+pub(crate) fn zero_blocks(block_device: &Arc<dyn BlockDevice>, start_block: usize, count: usize) {
+    invalidate_device_cache(block_device);
+    let align = block_device.alignment().max(core::mem::align_of::<u64>());
+    let layout = Layout::from_size_align(ZERO_BATCH_BLOCKS * BLOCK_SZ, align)
+        .expect("Invalid block device alignment!");
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        handle_alloc_error(layout);
+    }
+    let zeros = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+    for start in (0..count).step_by(ZERO_BATCH_BLOCKS) {
+        let blocks = ZERO_BATCH_BLOCKS.min(count - start);
+        block_device.write_blocks(start_block + start, &zeros[..blocks * BLOCK_SZ]);
+    }
+    unsafe { dealloc(ptr, layout) };
+}
+
+/// Write back exactly the listed blocks of a device in ascending order if they are
+/// modified, then flush the device. Return the number of blocks written.
+/// This is a write barrier for a set of blocks without syncing the whole cache.
+pub fn checkpoint(block_device: &Arc<dyn BlockDevice>, block_ids: &[usize]) -> usize {
+    let mut block_ids = block_ids.to_vec();
+    block_ids.sort_unstable();
+    block_ids.dedup();
+    let written = sync_blocks(block_device, &block_ids);
+    block_device.flush();
+    written
+}
+
+/// Write back all modified block caches in ascending block order,
+/// return the number of blocks written
+pub fn flush_dirty_sorted() -> usize {
+    let mut keys = resident_keys(|_| true);
+    keys.sort_unstable();
+    keys.into_iter()
+        .filter(|key| with_resident(*key, BlockCache::sync).unwrap_or(false))
+        .count()
+}
+
+/// Write back the listed blocks of a device if they are resident and modified,
+/// return the number of blocks written
+pub fn sync_blocks(block_device: &Arc<dyn BlockDevice>, block_ids: &[usize]) -> usize {
+    block_ids
+        .iter()
+        .filter(|block_id| {
+            let key = cache_key(**block_id, block_device);
+            with_resident(key, BlockCache::sync).unwrap_or(false)
+        })
+        .count()
+}
+
+/// Write back all modified block caches in ascending order as fast as possible,
+/// e.g. on an imminent power loss interrupt, return the number of blocks written.
+/// It neither allocates nor spins on a lock: if the interrupted code holds the lock
+/// of the cache manager nothing is written, and caches it holds are skipped.
+/// Writes are not verified.
+pub fn emergency_flush() -> usize {
+    let manager = match BLOCK_CACHE_MANAGER.try_lock() {
+        Some(manager) => manager,
+        None => return 0,
+    };
+    // 每次选出比上一个大的最小键，不需要额外的空间排序
+    let mut last: Option<CacheKey> = None;
+    let mut written = 0;
+    while let Some(entry) = manager
+        .queue
+        .iter()
+        .filter(|entry| last.is_none_or(|last| entry.key > last))
+        .min_by_key(|entry| entry.key)
+    {
+        last = Some(entry.key);
+        if let Some(mut cache) = entry.cache.try_lock() {
+            if cache.write_back() {
+                written += 1;
+            }
+        }
+    }
+    written
+}
+
+/// Write back the modified block caches of one device only, e.g. to unmount
+/// one of several filesystems, return the number of blocks written
+pub fn sync_device(block_device: &Arc<dyn BlockDevice>) -> usize {
+    let device = device_key(block_device);
+    resident_keys(|key| key.0 == device)
+        .into_iter()
+        .filter(|key| with_resident(*key, BlockCache::sync).unwrap_or(false))
+        .count()
+}
+
+/// Sync all block cache to block device,
+/// fail if any block doesn't pass write verification
+pub fn block_cache_try_sync_all() -> Result<(), EfsError> {
+    let mut result = Ok(());
+    for key in resident_keys(|_| true) {
+        if let Some(Err(err)) = with_resident(key, BlockCache::try_sync) {
+            result = Err(err);
+        }
+    }
+    result
+}
+
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
+    for key in resident_keys(|_| true) {
+        with_resident(key, BlockCache::sync);
     }
 }
+
+// 只复制出键，不持有块缓存的引用，同步期间其他线程仍然可以替换它们
+fn resident_keys(filter: impl Fn(&CacheKey) -> bool) -> Vec<CacheKey> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .map(|entry| entry.key)
+        .filter(filter)
+        .collect()
+}
+
+/*
+在释放管理器的锁之后再给块缓存加锁，否则持有这个块缓存的锁并等待管理器的线程
+会与这里互相等待。每次只持有一个块缓存的引用，其余块缓存仍然可以被替换。
+已经被替换掉的块缓存在丢弃时写回过了，返回 None
+*/
+fn with_resident<V>(key: CacheKey, f: impl FnOnce(&mut BlockCache) -> V) -> Option<V> {
+    assert_not_held(key);
+    let cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .find(|entry| entry.key == key)
+        .map(|entry| Arc::clone(&entry.cache))?;
+    let mut cache = cache.lock();
+    Some(f(&mut cache))
+}
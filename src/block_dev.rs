@@ -1,7 +1,146 @@
 use core::any::Any;
 
+use crate::{error::EfsError, BLOCK_SZ};
+
 // easy-fs 可以访问实现了 BlockDevice Trait 的块设备驱动程序
 pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Read `buf.len() / BLOCK_SZ` consecutive blocks starting at `block_id`,
+    /// drivers that can issue one request for all of them override it
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block);
+        }
+    }
+    /// Write `buf.len() / BLOCK_SZ` consecutive blocks starting at `block_id`,
+    /// drivers that can issue one request for all of them override it
+    fn write_blocks(&self, block_id: usize, buf: &[u8]) {
+        for (i, block) in buf.chunks_exact(BLOCK_SZ).enumerate() {
+            self.write_block(block_id + i, block);
+        }
+    }
+    /// Tell the device that `count` blocks starting at `block_id` are no longer in use,
+    /// drivers supporting TRIM can override it
+    fn discard(&self, _block_id: usize, _count: usize) {}
+    /// Make all completed writes durable, e.g. flush the write cache of the device
+    fn flush(&self) {}
+    /// Required alignment in bytes of the buffers handed to `read_block`/`write_block`,
+    /// e.g. for DMA-capable drivers. Block caches allocate their buffers accordingly.
+    fn alignment(&self) -> usize {
+        1
+    }
+    /// Number of blocks of the device, if the driver knows it
+    fn total_blocks(&self) -> Option<usize> {
+        None
+    }
+    /// Fallible version of `read_block`, drivers that can see transient errors
+    /// override it to report them instead of panicking
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), EfsError> {
+        self.read_block(block_id, buf);
+        Ok(())
+    }
+    /// Fallible version of `write_block`
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), EfsError> {
+        self.write_block(block_id, buf);
+        Ok(())
+    }
+}
+
+/// Wrap a block device to retry failed reads and writes.
+/// `backoff` is called with the number of failed attempts before each retry,
+/// since there is no way to sleep in `no_std`.
+pub struct RetryingBlockDevice<D, F> {
+    inner: D,
+    max_attempts: usize,
+    backoff: F,
+}
+
+impl<D, F> RetryingBlockDevice<D, F>
+where
+    D: BlockDevice,
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    pub fn new(inner: D, max_attempts: usize, backoff: F) -> Self {
+        assert!(max_attempts > 0);
+        Self {
+            inner,
+            max_attempts,
+            backoff,
+        }
+    }
+
+    fn retry(&self, mut op: impl FnMut() -> Result<(), EfsError>) -> Result<(), EfsError> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(_) if attempt < self.max_attempts => {
+                    (self.backoff)(attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<D, F> BlockDevice for RetryingBlockDevice<D, F>
+where
+    D: BlockDevice,
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        if let Err(err) = self.try_read_block(block_id, buf) {
+            panic!("Read block {} failed: {:?}", block_id, err);
+        }
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        if let Err(err) = self.try_write_block(block_id, buf) {
+            panic!("Write block {} failed: {:?}", block_id, err);
+        }
+    }
+    fn discard(&self, block_id: usize, count: usize) {
+        self.inner.discard(block_id, count)
+    }
+    fn flush(&self) {
+        self.inner.flush()
+    }
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+    fn total_blocks(&self) -> Option<usize> {
+        self.inner.total_blocks()
+    }
+    fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<(), EfsError> {
+        self.retry(|| self.inner.try_read_block(block_id, buf))
+    }
+    fn try_write_block(&self, block_id: usize, buf: &[u8]) -> Result<(), EfsError> {
+        self.retry(|| self.inner.try_write_block(block_id, buf))
+    }
+}
+
+/// A read-only block device backed by a filesystem image embedded in the kernel,
+/// e.g. one placed in flash/ROM with `include_bytes!`
+pub struct StaticBlockDevice {
+    image: &'static [u8],
+}
+
+impl StaticBlockDevice {
+    pub fn new(image: &'static [u8]) -> Self {
+        assert!(image.len().is_multiple_of(BLOCK_SZ));
+        Self { image }
+    }
+}
+
+impl BlockDevice for StaticBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let start = block_id * BLOCK_SZ;
+        buf.copy_from_slice(&self.image[start..start + BLOCK_SZ]);
+    }
+    fn write_block(&self, block_id: usize, _buf: &[u8]) {
+        panic!("Write block {} of a read-only image!", block_id);
+    }
+    fn total_blocks(&self) -> Option<usize> {
+        Some(self.image.len() / BLOCK_SZ)
+    }
 }
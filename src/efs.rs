@@ -1,13 +1,30 @@
 
-use alloc::sync::Arc;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::ops::{BitOr, Range};
 use spin::Mutex;
 
 use crate::{
     bitmap::Bitmap,
-    block_cache::{block_cache_sync_all, get_block_cache},
+    block_cache::{
+        block_cache_sync_all, get_block_cache, sync_blocks, zero_blocks, BLOCK_CACHE_MANAGER,
+    },
     block_dev::BlockDevice,
-    layout::{DiskInode, DiskInodeType, SuperBlock},
-    vfs::Inode,
+    error::{check_corrupt, ensure, EfsError},
+    layout::{
+        stamp_block_tag, DirEntry, DiskInode, DiskInodeType, SuperBlock, BLOCK_TAG_INODE,
+        DIRENT_SZ, DISK_INODE_SZ, INODES_PER_BLOCK, INODE_DIRECT_COUNT, LABEL_LEN, MAX_DIR_ENTRIES,
+        SB_FLAG_BLOCK_TAGS, SB_FLAG_CASE_INSENSITIVE, SB_FLAG_FREE_LIST, SB_FLAG_INLINE_DATA,
+        SB_FLAG_INODE_V2, SB_FLAG_SMALL_FANOUT, SUPER_BLOCK_SZ,
+    },
+    trace::{trace, TraceEvent},
+    vfs::{take_orphans, Inode},
     BLOCK_SZ,
 };
 
@@ -17,22 +34,187 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    data_area_blocks: u32,
+    max_dir_entries: u32,
+    // 空闲链表模式下链表头的块号和空闲块数，为 None 时使用数据位图
+    free_list: Option<(u32, u32)>,
+    case_insensitive: bool,
+    inline_data: bool,
+    // 顺序读时预读的数据块数，0 表示不预读
+    readahead_window: usize,
+    // 为 true 时破坏性操作只返回计划，不做修改
+    dry_run: bool,
+    // 为 true 时修改 size 或块指针的操作结束前把 inode 块和索引块刷到设备
+    sync_metadata: bool,
+    // 与超级块中的 epoch 保持一致
+    epoch: u64,
+}
+
+/// How free data blocks are tracked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataAllocator {
+    /// Scan the data bitmap for the first free bit
+    Bitmap,
+    /// Thread a linked list through the free blocks, each storing the id of
+    /// the next one. O(1) alloc and free, but without the locality of the bitmap.
+    FreeList,
+}
+
+/// Which free inode number to allocate next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeAllocPolicy {
+    /// Take the lowest free inode number, keeping the inode area compact
+    Lowest,
+    /// Continue after the last allocated inode number and wrap around, so that
+    /// a freed number is reused as late as possible and stale handles are more
+    /// likely to be caught by their generation
+    Cyclic,
+}
+
+/// Which side to believe when the size of an inode disagrees with its block mapping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeRepair {
+    /// Keep the size: map the missing index blocks, the data under them reads
+    /// as holes, and clear the slots past the size
+    TrustSize,
+    /// Keep the blocks: set the size to the end of the last data block mapped
+    /// in the data area, then fix the mapping up to it as with `TrustSize`
+    TrustBlocks,
+}
+
+/// A problem of a single inode found by `EasyFileSystem::check_inode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The inode number is out of range or not allocated
+    InodeNotAllocated,
+    /// The size disagrees with the block mapping, see `check_inode_sizes`
+    SizeMismatch,
+    /// A data or index block id lies outside the data area
+    BlockOutOfRange(u32),
+    /// A data or index block is not marked allocated
+    BlockNotAllocated(u32),
+    /// The directory entry at this index refers to an inode number that is
+    /// out of range or not allocated
+    BadDirEntry(usize),
+}
+
+/// Region boundaries of a mounted filesystem, in blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayoutInfo {
+    pub total_blocks: u32,
+    pub inode_bitmap_blocks: u32,
+    pub inode_area_blocks: u32,
+    pub data_bitmap_blocks: u32,
+    pub data_area_blocks: u32,
+    pub inode_start_block: u32,
+    pub data_start_block: u32,
+    pub inodes_per_block: u32,
+    pub inode_size: u32,
+}
+
+/// Flags of `EasyFileSystem::open_file`, combined with `|`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// Create a regular file if the path doesn't exist, like `O_CREAT`
+    pub const CREATE: Self = Self(1);
+    /// Together with `CREATE`, fail if the path exists, like `O_EXCL`
+    pub const EXCL: Self = Self(1 << 1);
+    /// Truncate an existing regular file to zero, like `O_TRUNC`
+    pub const TRUNC: Self = Self(1 << 2);
+
+    /// No flags: open an existing file or directory as is
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+    /// Whether all flags of `other` are set
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Sizes and fragmentation of the regular files reachable from the root directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsageReport {
+    /// Number of files by size: empty, at most one block, within the direct
+    /// blocks, and larger
+    pub size_histogram: [usize; 4],
+    /// Number of data blocks of the files
+    pub data_blocks: usize,
+    /// Number of runs of consecutive data blocks of the files
+    pub extents: usize,
+}
+
+impl UsageReport {
+    /// Extents per data block, from close to 0 when every file is contiguous
+    /// to 1 when no two blocks of a file are adjacent
+    pub fn fragmentation(&self) -> f32 {
+        if self.data_blocks == 0 {
+            0.0
+        } else {
+            self.extents as f32 / self.data_blocks as f32
+        }
+    }
+}
+
 type DataBlock = [u8; BLOCK_SZ];
 impl EasyFileSystem {
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
+    ) -> Arc<Mutex<Self>> {
+        Self::create_with(
+            block_device,
+            total_blocks,
+            inode_bitmap_blocks,
+            DataAllocator::Bitmap,
+        )
+    }
+
+    /// Same as `create`, and save a label and a UUID in the superblock to identify the image
+    pub fn create_with_label(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        label: [u8; LABEL_LEN],
+        uuid: [u8; 16],
+    ) -> Arc<Mutex<Self>> {
+        let efs = Self::create(block_device, total_blocks, inode_bitmap_blocks);
+        efs.lock().modify_super_block(|super_block| {
+            super_block.label = label;
+            super_block.uuid = uuid;
+        });
+        block_cache_sync_all();
+        efs
+    }
+
+    /// Same as `create`, but choose how free data blocks are tracked
+    pub fn create_with(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        allocator: DataAllocator,
     ) -> Arc<Mutex<Self>> {
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
-        let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        // 一个 DiskInode 不会跨越两个块
+        let inode_area_blocks = inode_num.div_ceil(INODES_PER_BLOCK) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
         let data_total_blocks = total_blocks - 1 - inode_total_blocks;
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+        let data_bitmap_blocks = data_total_blocks.div_ceil(4097);
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
             (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
@@ -44,21 +226,23 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
+            max_dir_entries: MAX_DIR_ENTRIES,
+            free_list: None,
+            case_insensitive: false,
+            inline_data: false,
+            readahead_window: 0,
+            dry_run: false,
+            sync_metadata: false,
+            epoch: 0,
         };
-        // clear all blocks
-        for i in 0..total_blocks {
-            get_block_cache(i as usize, Arc::clone(&block_device))
-                .lock()
-                .modify(0, |data_block: &mut DataBlock| {
-                    for byte in data_block.iter_mut() {
-                        *byte = 0;
-                    }
-                });
-        }
+        // clear all blocks, 绕过块缓存成批写入，不挤掉其他设备有用的缓存
+        zero_blocks(&block_device, 0, total_blocks as usize);
         // initialize SuperBlock
         get_block_cache(0, Arc::clone(&block_device)).lock().modify(
             0,
-            |super_block: &mut SuperBlock| {
+            |raw: &mut [u8; SUPER_BLOCK_SZ]| {
+                let mut super_block = SuperBlock::from_le_bytes(raw);
                 super_block.initialize(
                     total_blocks,
                     inode_bitmap_blocks,
@@ -66,26 +250,97 @@ impl EasyFileSystem {
                     data_bitmap_blocks,
                     data_area_blocks,
                 );
+                super_block.flags |= SB_FLAG_INODE_V2;
+                if cfg!(feature = "block-tags") {
+                    super_block.flags |= SB_FLAG_BLOCK_TAGS;
+                }
+                if cfg!(feature = "small-fanout") {
+                    super_block.flags |= SB_FLAG_SMALL_FANOUT;
+                }
+                *raw = super_block.to_le_bytes();
             },
         );
-        // 创建根目录
-        assert_eq!(efs.alloc_inode(),0);
-        let (root_inode_block_id,root_inode_offset) = efs.get_disk_inode_pos(0);
+        for i in 0..inode_area_blocks {
+            stamp_block_tag(
+                (efs.inode_area_start_block + i) as usize,
+                BLOCK_TAG_INODE,
+                &block_device,
+            );
+        }
+        if allocator == DataAllocator::FreeList {
+            // 把所有数据块按块号顺序串成空闲链表，最后一块的后继为 0
+            let start = efs.data_area_start_block;
+            for i in 0..data_area_blocks {
+                let next = if i + 1 < data_area_blocks {
+                    start + i + 1
+                } else {
+                    0
+                };
+                get_block_cache((start + i) as usize, Arc::clone(&block_device))
+                    .lock()
+                    .modify(0, |raw: &mut [u8; 4]| *raw = next.to_le_bytes());
+            }
+            efs.free_list = Some((
+                if data_area_blocks > 0 { start } else { 0 },
+                data_area_blocks,
+            ));
+            efs.save_free_list();
+        }
+        // 创建根目录，其 .. 指向自身
+        assert_eq!(efs.inode_bitmap.alloc(&block_device), Some(0));
+        efs.bump_epoch();
+        let root_data_block = efs
+            .alloc_data()
+            .expect("No data block for the root directory!");
+        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
             .lock()
             .modify(root_inode_offset, |disk_inode: &mut DiskInode|{
                 disk_inode.initialize(DiskInodeType::Directory);
+                disk_inode.init_dot_entries(0, 0, vec![root_data_block], &block_device);
             });
         block_cache_sync_all();
         Arc::new(Mutex::new(efs))
     }
 
     pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+        match Self::try_open(block_device) {
+            Ok(efs) => efs,
+            Err(err) => panic!("Error loading EFS: {:?}", err),
+        }
+    }
+
+    /// Same as `open`, but fail instead of panicking if the superblock is invalid,
+    /// the device is smaller than the superblock claims, inode 0 is not the root
+    /// directory or the block cache is too small for the files the filesystem can hold
+    pub fn try_open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>, EfsError> {
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
-            .lock()
-            .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+        let efs = get_block_cache(0, Arc::clone(&block_device)).lock().read(
+            0,
+            |raw: &[u8; SUPER_BLOCK_SZ]| {
+                let super_block = SuperBlock::from_le_bytes(raw);
+                // 带块类型标签或 small-fanout 的镜像中索引块的布局不同，必须使用相同的 feature 打开；
+                // 旧的 inode 布局没有配额等字段，无法按当前的 DiskInode 读取
+                if !super_block.is_valid()
+                    || super_block.flags & SB_FLAG_INODE_V2 == 0
+                    || (super_block.flags & SB_FLAG_BLOCK_TAGS != 0) != cfg!(feature = "block-tags")
+                    || (super_block.flags & SB_FLAG_SMALL_FANOUT != 0)
+                        != cfg!(feature = "small-fanout")
+                {
+                    return Err(EfsError::InvalidSuperBlock);
+                }
+                // DiskInode 的大小改变后每块的 inode 数不同，inode 区可能放不下位图中的所有 inode
+                let inode_num = super_block.inode_bitmap_blocks as usize * BLOCK_SZ * 8;
+                if (super_block.inode_area_blocks as usize) * INODES_PER_BLOCK < inode_num {
+                    return Err(EfsError::InvalidSuperBlock);
+                }
+                // 截断的镜像中，高编号的块读出来是 0 甚至越界
+                if block_device
+                    .total_blocks()
+                    .is_some_and(|blocks| blocks < super_block.total_blocks as usize)
+                {
+                    return Err(EfsError::DeviceTooSmall);
+                }
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let efs = Self {
@@ -97,53 +352,1043 @@ impl EasyFileSystem {
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
+                    max_dir_entries: match super_block.max_dir_entries {
+                        0 => MAX_DIR_ENTRIES,
+                        max => max,
+                    },
+                    free_list: (super_block.flags & SB_FLAG_FREE_LIST != 0)
+                        .then_some((super_block.free_list_head, super_block.free_list_len)),
+                    case_insensitive: super_block.flags & SB_FLAG_CASE_INSENSITIVE != 0,
+                    inline_data: super_block.flags & SB_FLAG_INLINE_DATA != 0,
+                    readahead_window: 0,
+                    dry_run: false,
+                    sync_metadata: false,
+                    epoch: super_block.epoch,
                 };
-                Arc::new(Mutex::new(efs))
+                Ok(efs)
+            },
+        )?;
+        if !efs.has_root() {
+            return Err(EfsError::NoRoot);
+        }
+        // 缓存不足时，操作会在替换块缓存时因所有块都在使用而 panic
+        if BLOCK_CACHE_MANAGER
+            .lock()
+            .device_capacity(&efs.block_device)
+            < efs.min_cache_blocks()
+        {
+            return Err(EfsError::CacheTooSmall);
+        }
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+
+    /// Whether inode 0 is allocated and is a directory, every path is resolved from it
+    fn has_root(&self) -> bool {
+        if !self.inode_bitmap.is_allocated(&self.block_device, 0) {
+            return false;
+        }
+        let (block_id, block_offset) = self.get_disk_inode_pos(0);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, |raw: &[u8; DISK_INODE_SZ]| {
+                DiskInode::from_le_bytes(raw).is_some_and(|disk_inode| disk_inode.is_dir())
+            })
+    }
+
+    /// Same as `try_open`, but let this mount keep at most `cache_capacity`
+    /// block caches of its device, independently of other devices
+    pub fn try_open_with_cache(
+        block_device: Arc<dyn BlockDevice>,
+        cache_capacity: usize,
+    ) -> Result<Arc<Mutex<Self>>, EfsError> {
+        BLOCK_CACHE_MANAGER
+            .lock()
+            .set_device_capacity(&block_device, Some(cache_capacity));
+        let result = Self::try_open(Arc::clone(&block_device));
+        if result.is_err() {
+            BLOCK_CACHE_MANAGER
+                .lock()
+                .set_device_capacity(&block_device, None);
+        }
+        result
+    }
+
+    /// Number of block caches an operation may keep in use at the same time:
+    /// the block of a disk inode plus the index blocks down to the deepest
+    /// level a file can reach, or one more block when files have no index blocks
+    pub fn min_cache_blocks(&self) -> usize {
+        1 + DiskInode::index_depth(self.data_area_blocks).max(1)
+    }
+
+    /// Read back the superblock, bitmaps and inode area after writing them and
+    /// report mismatches through `block_cache_try_sync_all`. This doubles the I/O
+    /// of these blocks, so it is off by default.
+    pub fn set_verify_writes(&self, verify: bool) {
+        BLOCK_CACHE_MANAGER.lock().set_verify_writes(
+            &self.block_device,
+            verify.then_some(self.data_area_start_block as usize),
+        );
+    }
+
+    /// Find the inodes reachable from the root directory whose size disagrees with
+    /// their block mapping, e.g. after a crash in the middle of a resize, and repair
+    /// them as `repair` says or only report them if None or in dry-run mode. Return the
+    /// ids of the inodes found; an inode is left as it is if there are no free blocks to repair it.
+    /// Blocks unmapped by the repair are freed unless another inode maps them
+    /// or the free list is in use.
+    pub fn check_inode_sizes(&mut self, repair: Option<SizeRepair>) -> Vec<u32> {
+        let mut found = Vec::new();
+        self.walk_inodes(|inode_id, disk_inode| {
+            let (missing, extra) = disk_inode.mapping_mismatch(&self.block_device);
+            if missing > 0 || extra {
+                found.push(inode_id);
+            }
+        });
+        let Some(repair) = repair.filter(|_| !self.dry_run) else {
+            return found;
+        };
+        let data_area = self.data_area();
+        let block_device = Arc::clone(&self.block_device);
+        let mut cleared = Vec::new();
+        for &inode_id in found.iter() {
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&block_device))
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    if repair == SizeRepair::TrustBlocks {
+                        let blocks = disk_inode
+                            .last_mapped_block(
+                                |block_id| data_area.contains(&block_id),
+                                &block_device,
+                            )
+                            .map_or(0, |inner_id| inner_id + 1);
+                        let mapped = blocks * BLOCK_SZ as u32;
+                        if disk_inode.size.div_ceil(BLOCK_SZ as u32) > blocks {
+                            disk_inode.size = mapped;
+                        }
+                        if disk_inode.reserved > disk_inode.size {
+                            // 预留的块不是文件内容，超出 size 的映射归入预留
+                            disk_inode.reserved = if mapped > disk_inode.size { mapped } else { 0 };
+                        } else if disk_inode.size.div_ceil(BLOCK_SZ as u32) != blocks {
+                            // 最后一块仍是原来的最后一块时保留原来的大小
+                            disk_inode.size = mapped;
+                        }
+                    }
+                    let (missing, _) = disk_inode.mapping_mismatch(&block_device);
+                    let Some(new_blocks) = self.alloc_data_blocks(missing) else {
+                        return;
+                    };
+                    cleared.extend(disk_inode.fit_mapping_to_size(
+                        new_blocks,
+                        |block_id| data_area.contains(&block_id),
+                        &block_device,
+                    ));
+                });
+        }
+        // 清掉的块若不再被任何 inode 使用就释放。空闲链表模式下无法判断块是否已经空闲，
+        // 重复释放会破坏链表，只能留着
+        if self.free_list.is_none() {
+            let mut in_use = BTreeSet::new();
+            self.walk_inodes(|_, disk_inode| {
+                in_use.extend(disk_inode.mapped_block_ids(
+                    |block_id| data_area.contains(&block_id),
+                    &self.block_device,
+                ));
+            });
+            cleared.sort_unstable();
+            cleared.dedup();
+            cleared.retain(|block_id| data_area.contains(block_id) && !in_use.contains(block_id));
+            self.dealloc_data_blocks(&cleared);
+        }
+        block_cache_sync_all();
+        found
+    }
+
+    /// Check a single inode: its block ids lie in the data area and are allocated,
+    /// its size agrees with its block mapping and, for a directory, its entries
+    /// refer to allocated inodes. Cheap enough to scan the inodes one by one in
+    /// the background, except with `DataAllocator::FreeList`, where the whole
+    /// free list is walked to tell whether the blocks are allocated.
+    pub fn check_inode(&self, inode_id: u32) -> Vec<Issue> {
+        if !self
+            .inode_bitmap
+            .is_allocated(&self.block_device, inode_id as usize)
+        {
+            return vec![Issue::InodeNotAllocated];
+        }
+        let data_area = self.data_area();
+        let free_list = self.free_list_blocks();
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| {
+                let mut issues = Vec::new();
+                let (missing, extra) = disk_inode.mapping_mismatch(&self.block_device);
+                if missing > 0 || extra {
+                    issues.push(Issue::SizeMismatch);
+                }
+                disk_inode.walk_mapping(
+                    |block_id| data_area.contains(&block_id),
+                    &self.block_device,
+                    |block_id, _| {
+                        if !data_area.contains(&block_id) {
+                            issues.push(Issue::BlockOutOfRange(block_id));
+                        } else if !self.is_data_allocated(block_id, &free_list) {
+                            issues.push(Issue::BlockNotAllocated(block_id));
+                        }
+                    },
+                );
+                // 块号有问题时目录项读出来的可能是任意数据
+                if !disk_inode.is_dir() || !issues.is_empty() {
+                    return issues;
+                }
+                let mut dirent = DirEntry::empty();
+                for idx in 0..disk_inode.size as usize / DIRENT_SZ {
+                    disk_inode.read_at(idx * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                    if !self
+                        .inode_bitmap
+                        .is_allocated(&self.block_device, dirent.inode_number() as usize)
+                    {
+                        issues.push(Issue::BadDirEntry(idx));
+                    }
+                }
+                issues
+            })
+    }
+
+    /// Histogram of file sizes and fragmentation of their data blocks, for capacity planning
+    pub fn usage_report(&self) -> UsageReport {
+        let mut report = UsageReport::default();
+        self.walk_inodes(|_, disk_inode| {
+            if !disk_inode.is_file() {
+                return;
+            }
+            let size = disk_inode.size as usize;
+            let bucket = if size == 0 {
+                0
+            } else if size <= BLOCK_SZ {
+                1
+            } else if size <= INODE_DIRECT_COUNT * BLOCK_SZ {
+                2
+            } else {
+                3
+            };
+            report.size_histogram[bucket] += 1;
+            let data_block_ids = disk_inode.data_block_ids(&self.block_device);
+            report.data_blocks += data_block_ids.len();
+            report.extents += data_block_ids
+                .iter()
+                .enumerate()
+                .filter(|&(i, block_id)| i == 0 || data_block_ids[i - 1] + 1 != *block_id)
+                .count();
+        });
+        report
+    }
+
+    // 从根目录开始遍历目录树，对每个可达的 inode 调用一次 f，
+    // 调用时持有该 inode 所在块的块缓存的锁
+    fn walk_inodes(&self, mut f: impl FnMut(u32, &DiskInode)) {
+        let mut visited = vec![false; self.inode_bitmap.maximum()];
+        let mut stack = vec![0u32];
+        while let Some(inode_id) = stack.pop() {
+            if visited[inode_id as usize] {
+                continue;
+            }
+            visited[inode_id as usize] = true;
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+            let children = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    f(inode_id, disk_inode);
+                    self.child_entries(disk_inode)
+                });
+            stack.extend(
+                children
+                    .into_iter()
+                    .map(|(_, child)| child)
+                    .filter(|&child| (child as usize) < visited.len()),
+            );
+        }
+    }
+
+    /// Renumber the inodes reachable from the root directory to the lowest free
+    /// inode numbers, highest first, and rewrite every directory entry referring
+    /// to a moved inode. Return the number of inodes moved. This is an offline
+    /// operation: handles opened before refer to the old numbers and go stale.
+    /// Takes `&mut self` rather than `&self`, as releasing the old numbers advances
+    /// the epoch. Fails with `EfsError::Corrupt`, leaving a directory tree that is
+    /// still consistent, if the inode bitmap disagrees with the tree.
+    pub fn compact_inodes(&mut self) -> Result<usize, EfsError> {
+        let mut used = Vec::new();
+        self.walk_inodes(|inode_id, _| used.push(inode_id));
+        used.sort_unstable();
+        if used.iter().any(|&inode_id| {
+            !self
+                .inode_bitmap
+                .is_allocated(&self.block_device, inode_id as usize)
+        }) {
+            return Err(EfsError::Corrupt);
+        }
+        // 旧编号 -> 新编号。高编号依次搬到最低的空闲编号，腾出的编号总比新编号大
+        let mut moves: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut result = Ok(());
+        let mut target = 1;
+        for &inode_id in used.iter().rev() {
+            while target < inode_id
+                && self
+                    .inode_bitmap
+                    .is_allocated(&self.block_device, target as usize)
+            {
+                target += 1;
+            }
+            if target >= inode_id {
+                break;
+            }
+            // 已经搬走的 inode 仍要改写目录项，出错时先停止搬迁
+            if !self
+                .inode_bitmap
+                .alloc_at(&self.block_device, target as usize)
+            {
+                result = Err(EfsError::Corrupt);
+                break;
+            }
+            self.copy_disk_inode(inode_id, target);
+            if !self.dealloc_inode(inode_id) {
+                result = Err(EfsError::Corrupt);
+            }
+            moves.insert(inode_id, target);
+            if result.is_err() {
+                break;
+            }
+            target += 1;
+        }
+        if moves.is_empty() {
+            return result.map(|_| 0);
+        }
+        let moved = |inode_id: u32| moves.get(&inode_id).copied().unwrap_or(inode_id);
+        let dirs: Vec<u32> = used.into_iter().map(moved).collect();
+        for dir_id in dirs {
+            let (block_id, block_offset) = self.get_disk_inode_pos(dir_id);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    if !disk_inode.is_dir() {
+                        return;
+                    }
+                    // . 和 .. 也要改写
+                    let mut dirent = DirEntry::empty();
+                    for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                        disk_inode.read_at(
+                            i * DIRENT_SZ,
+                            dirent.as_bytes_mut(),
+                            &self.block_device,
+                        );
+                        let inode_id = dirent.inode_number();
+                        if moved(inode_id) != inode_id {
+                            dirent.set_inode_number(moved(inode_id));
+                            disk_inode.write_at(
+                                i * DIRENT_SZ,
+                                dirent.as_bytes(),
+                                &self.block_device,
+                            );
+                        }
+                    }
+                });
+        }
+        block_cache_sync_all();
+        result.map(|_| moves.len())
+    }
+
+    // 把一个 DiskInode 原样复制到另一个编号，旧位置的代数加一，指向它的句柄随之失效。
+    // 新位置的代数比两者原来的代数都大，指向新位置上原来的 inode 的过期句柄也不会匹配
+    fn copy_disk_inode(&self, from: u32, to: u32) {
+        let (block_id, block_offset) = self.get_disk_inode_pos(from);
+        let raw = {
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+            let mut block_cache = block_cache.lock();
+            let raw = block_cache.read(block_offset, |raw: &[u8; DISK_INODE_SZ]| *raw);
+            block_cache.modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.generation = disk_inode.generation.wrapping_add(1);
+            });
+            raw
+        };
+        let (block_id, block_offset) = self.get_disk_inode_pos(to);
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        let old_generation =
+            block_cache.read(block_offset, |disk_inode: &DiskInode| disk_inode.generation);
+        block_cache.modify(block_offset, |raw_to: &mut [u8; DISK_INODE_SZ]| {
+            *raw_to = raw
+        });
+        block_cache.modify(block_offset, |disk_inode: &mut DiskInode| {
+            disk_inode.generation = old_generation.max(disk_inode.generation).wrapping_add(1);
+        });
+    }
+
+    /// Find a path from the root directory to an inode by searching the whole
+    /// tree, e.g. for logging. With several links to the inode any one of them is returned.
+    pub fn path_of(&self, inode_id: u32) -> Option<String> {
+        if inode_id == 0 {
+            return Some(String::from("/"));
+        }
+        let mut visited = vec![false; self.inode_bitmap.maximum()];
+        let mut stack = vec![(0u32, String::new())];
+        while let Some((dir_id, dir_path)) = stack.pop() {
+            if visited[dir_id as usize] {
+                continue;
+            }
+            visited[dir_id as usize] = true;
+            let (block_id, block_offset) = self.get_disk_inode_pos(dir_id);
+            let children = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    self.child_entries(disk_inode)
+                });
+            for (name, child) in children {
+                let path = format!("{}/{}", dir_path, name);
+                if child == inode_id {
+                    return Some(path);
+                }
+                if (child as usize) < visited.len() {
+                    stack.push((child, path));
+                }
+            }
+        }
+        None
+    }
+
+    // 目录中除 . 和 .. 之外的目录项，不是目录时为空
+    /// Positions of the directories with a quota which have an entry for `inode_id`
+    pub(crate) fn quota_parents(&self, inode_id: u32) -> Vec<(usize, usize)> {
+        let mut parents = Vec::new();
+        self.walk_inodes(|dir_id, disk_inode| {
+            if disk_inode.quota != 0
+                && self
+                    .child_entries(disk_inode)
+                    .iter()
+                    .any(|&(_, child)| child == inode_id)
+            {
+                let (block_id, block_offset) = self.get_disk_inode_pos(dir_id);
+                parents.push((block_id as usize, block_offset));
+            }
+        });
+        parents
+    }
+
+    fn child_entries(&self, disk_inode: &DiskInode) -> Vec<(String, u32)> {
+        if !disk_inode.is_dir() {
+            return Vec::new();
+        }
+        let mut dirent = DirEntry::empty();
+        (0..disk_inode.size as usize / DIRENT_SZ)
+            .filter_map(|i| {
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                (!matches!(dirent.name_bytes(), b"." | b".."))
+                    .then(|| (String::from(dirent.name()), dirent.inode_number()))
+            })
+            .collect()
+    }
+
+    /// A copy of the superblock as currently cached, for diagnostic tools
+    pub fn super_block(&self) -> SuperBlock {
+        self.read_super_block(|super_block| *super_block)
+    }
+
+    /// Get the region boundaries, as computed when the filesystem was created
+    pub fn layout_info(&self) -> LayoutInfo {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |raw: &[u8; SUPER_BLOCK_SZ]| {
+                let super_block = SuperBlock::from_le_bytes(raw);
+                LayoutInfo {
+                    total_blocks: super_block.total_blocks,
+                    inode_bitmap_blocks: super_block.inode_bitmap_blocks,
+                    inode_area_blocks: super_block.inode_area_blocks,
+                    data_bitmap_blocks: super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
+                    inode_start_block: self.inode_area_start_block,
+                    data_start_block: self.data_area_start_block,
+                    inodes_per_block: INODES_PER_BLOCK as u32,
+                    inode_size: DISK_INODE_SZ as u32,
+                }
             })
     }
 
+    /// The label saved in the superblock, zeroed unless one was given
+    pub fn label(&self) -> [u8; LABEL_LEN] {
+        self.read_super_block(|super_block| super_block.label)
+    }
+
+    /// Replace the label saved in the superblock
+    pub fn set_label(&mut self, label: [u8; LABEL_LEN]) {
+        self.modify_super_block(|super_block| super_block.label = label);
+        block_cache_sync_all();
+    }
+
+    /// The UUID given when the filesystem was created, zeroed if none was
+    pub fn uuid(&self) -> [u8; 16] {
+        self.read_super_block(|super_block| super_block.uuid)
+    }
+
+    /// Max number of entries in a directory
+    pub fn max_dir_entries(&self) -> u32 {
+        self.max_dir_entries
+    }
+
+    /// Limit the number of entries in each directory and save the limit in the
+    /// superblock. Creating more entries fails with `EfsError::DirFull`, while
+    /// directories which already have more entries are kept as they are.
+    pub fn set_max_dir_entries(&mut self, max: u32) {
+        let max = max.min(MAX_DIR_ENTRIES);
+        self.max_dir_entries = max;
+        self.modify_super_block(|super_block| super_block.max_dir_entries = max);
+        block_cache_sync_all();
+    }
+
+    /// Whether names are looked up ignoring ASCII case
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Look up names ignoring ASCII case or not, and save the choice in the superblock.
+    /// Names keep the case they are created with. Turning it on for a filesystem
+    /// which has names differing only in case makes all but the first one unreachable.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+        self.set_super_block_flag(SB_FLAG_CASE_INSENSITIVE, case_insensitive);
+        block_cache_sync_all();
+    }
+
+    /// Whether new regular files keep their content inside the inode while it is small
+    pub fn is_inline_data(&self) -> bool {
+        self.inline_data
+    }
+
+    /// Store the content of new regular files inside the inode, in place of the
+    /// block pointers, until it grows beyond `INLINE_DATA_SZ` bytes. Saved in the
+    /// superblock; existing files keep their representation.
+    pub fn set_inline_data(&mut self, inline_data: bool) {
+        self.inline_data = inline_data;
+        self.set_super_block_flag(SB_FLAG_INLINE_DATA, inline_data);
+        block_cache_sync_all();
+    }
+
+    fn set_super_block_flag(&self, flag: u32, value: bool) {
+        self.modify_super_block(|super_block| {
+            if value {
+                super_block.flags |= flag;
+            } else {
+                super_block.flags &= !flag;
+            }
+        });
+    }
+
+    fn read_super_block<V>(&self, f: impl FnOnce(&SuperBlock) -> V) -> V {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |raw: &[u8; SUPER_BLOCK_SZ]| {
+                f(&SuperBlock::from_le_bytes(raw))
+            })
+    }
+
+    fn modify_super_block(&self, f: impl FnOnce(&mut SuperBlock)) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |raw: &mut [u8; SUPER_BLOCK_SZ]| {
+                let mut super_block = SuperBlock::from_le_bytes(raw);
+                f(&mut super_block);
+                *raw = super_block.to_le_bytes();
+            });
+    }
+
+    /// Whether a name on disk matches a name being looked up
+    pub fn names_match(&self, on_disk: &[u8], name: &str) -> bool {
+        if self.case_insensitive {
+            on_disk.eq_ignore_ascii_case(name.as_bytes())
+        } else {
+            on_disk == name.as_bytes()
+        }
+    }
+
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
         let (block_id, block_offseet) = efs.lock().get_disk_inode_pos(0);
         Inode::new(block_id, block_offseet, Arc::clone(efs), block_device)
     }
 
+    /// Get the directory at `path` as the root of a confined view: paths
+    /// resolved from it with `Inode::find_path` can't escape it through `..`
+    pub fn subtree_root(efs: &Arc<Mutex<Self>>, path: &str) -> Option<Arc<Inode>> {
+        Self::root_inode(efs)
+            .find_path(path)
+            .filter(|inode| inode.is_dir())
+    }
+
+    /// Resolve `path` relative to `root` like `open(2)`: with `OpenFlags::CREATE`
+    /// a missing regular file is created in its parent directory, `EXCL` makes
+    /// an existing path an error, and `TRUNC` empties an existing regular file
+    pub fn open_file(root: &Inode, path: &str, flags: OpenFlags) -> Result<Arc<Inode>, EfsError> {
+        let inode = match root.find_path(path) {
+            Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) => {
+                return Err(EfsError::AlreadyExists);
+            }
+            Some(inode) => inode,
+            None if !flags.contains(OpenFlags::CREATE) => return Err(EfsError::NotFound),
+            None => {
+                let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+                if matches!(name, "" | "." | "..") {
+                    return Err(EfsError::NotFound);
+                }
+                let dir = root.find_path(dir_path).ok_or(EfsError::NotFound)?;
+                if !dir.is_dir() {
+                    return Err(EfsError::NotDir);
+                }
+                match dir.try_create_inode(name, DiskInodeType::File) {
+                    // 新建的文件不需要截断
+                    Ok(inode) => return Ok(inode),
+                    // 查找之后、创建之前被别人创建了
+                    Err(EfsError::AlreadyExists) if !flags.contains(OpenFlags::EXCL) => {
+                        dir.find(name).ok_or(EfsError::NotFound)?
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        };
+        if flags.contains(OpenFlags::TRUNC) {
+            if inode.is_dir() {
+                return Err(EfsError::IsDir);
+            }
+            inode.try_clear()?;
+        }
+        Ok(inode)
+    }
+
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        let inode_size = core::mem::size_of::<DiskInode>();
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        let inodes_per_block = INODES_PER_BLOCK as u32;
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (
             block_id,
-            (inode_id % inodes_per_block) as usize * inode_size,
+            (inode_id % inodes_per_block) as usize * DISK_INODE_SZ,
         )
     }
 
+    /// Inode number of the disk inode at the given position
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        (block_id - self.inode_area_start_block) * INODES_PER_BLOCK as u32
+            + (block_offset / DISK_INODE_SZ) as u32
+    }
+
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
 
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Spread inode and data block allocations over the whole device instead
+    /// of always taking the lowest free one, which evens out flash wear.
+    /// Off by default, so that allocations are deterministic.
+    pub fn set_rotating_alloc(&mut self, rotating: bool) {
+        self.inode_bitmap.set_rotating(rotating);
+        self.data_bitmap.set_rotating(rotating);
+    }
+
+    /// Set how inode numbers are allocated, `InodeAllocPolicy::Lowest` by default.
+    /// Not saved on disk.
+    pub fn set_inode_alloc_policy(&mut self, policy: InodeAllocPolicy) {
+        self.inode_bitmap.set_rotating(policy == InodeAllocPolicy::Cyclic);
+    }
+
+    /// Current inode allocation policy
+    pub fn inode_alloc_policy(&self) -> InodeAllocPolicy {
+        if self.inode_bitmap.is_rotating() {
+            InodeAllocPolicy::Cyclic
+        } else {
+            InodeAllocPolicy::Lowest
+        }
+    }
+
+    /// Load up to `blocks` data blocks past the end of a read into the cache when the
+    /// read continues where the previous read of the same handle stopped.
+    /// 0 turns read-ahead off, which is the default. Not saved on disk.
+    pub fn set_readahead_window(&mut self, blocks: usize) {
+        self.readahead_window = blocks;
+    }
+
+    /// Number of blocks read ahead of sequential reads. The configured window is
+    /// clamped so that the prefetched blocks leave room in the block cache for
+    /// the blocks an operation keeps in use.
+    pub fn readahead_window(&self) -> usize {
+        let capacity = BLOCK_CACHE_MANAGER.lock().capacity();
+        self.readahead_window
+            .min(capacity.saturating_sub(self.min_cache_blocks()))
+    }
+
+    /// Make `Inode::remove_all`, `Inode::unlink`, `Inode::clear` and `check_inode_sizes`
+    /// only report the inodes and blocks they would release or repair, without changing anything.
+    /// Not saved on disk.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether destructive operations only report what they would do
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Make writes, `reserve`, `punch_hole`, `clear` and the directory operations flush
+    /// the inode blocks and the index blocks of the inodes they change, the directory
+    /// as well as the child, to the device before they return, so that size and block
+    /// pointers survive a power loss. Data blocks are left in the write cache of the
+    /// device until its next flush. Off by default, not saved on disk.
+    pub fn set_sync_metadata(&mut self, sync_metadata: bool) {
+        self.sync_metadata = sync_metadata;
+    }
+
+    /// Whether metadata changes are flushed to the device after each operation
+    pub fn is_sync_metadata(&self) -> bool {
+        self.sync_metadata
+    }
+
+    /// Allocate an inode number, return None if all inodes are in use
+    pub fn alloc_inode(&mut self) -> Option<u32> {
+        self.reap_orphans();
+        let mut inode_id = self.inode_bitmap.alloc(&self.block_device)? as u32;
+        // 0 号 inode 只能是根目录，位图损坏时它的位保持占用，再分配一个
+        if inode_id == 0 {
+            inode_id = self.inode_bitmap.alloc(&self.block_device)? as u32;
+        }
+        self.bump_epoch();
+        Some(inode_id)
+    }
+    /// Release all the blocks of a disk inode as well as its inode number
+    pub(crate) fn release_inode(&mut self, inode_id: u32) -> Result<(), EfsError> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let data_blocks_dealloc =
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.clear_size(&self.block_device)
+                });
+        ensure!(
+            self.dealloc_data_blocks(&data_blocks_dealloc),
+            EfsError::Corrupt
+        );
+        ensure!(self.dealloc_inode(inode_id), EfsError::Corrupt);
+        Ok(())
+    }
+
+    /// Release the inodes unlinked while open whose last handle has been dropped
+    /// since, return how many were released
+    pub fn reap(&mut self) -> usize {
+        let released = self.reap_orphans();
+        block_cache_sync_all();
+        released
+    }
+
+    pub(crate) fn reap_orphans(&mut self) -> usize {
+        let mut released = 0;
+        for (block_id, block_offset, generation) in take_orphans(&self.block_device) {
+            let inode_id = self.get_inode_id(block_id as u32, block_offset);
+            // 设备的地址可能被另一个设备重用，只释放仍处于删除状态的同一个 inode
+            let orphaned = self
+                .inode_bitmap
+                .is_allocated(&self.block_device, inode_id as usize)
+                && get_block_cache(block_id, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(block_offset, |disk_inode: &DiskInode| {
+                        disk_inode.generation == generation && disk_inode.nlink == 0
+                    });
+            // 磁盘数据不一致时放弃释放
+            if orphaned && self.release_inode(inode_id).is_ok() {
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Release an inode number, return false if it is not allocated
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> bool {
+        let released = self
+            .inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize);
+        if released {
+            self.bump_epoch();
+        }
+        released
+    }
+
+    /// A counter saved in the superblock which grows with every metadata change:
+    /// allocating or releasing inodes and data blocks, and renaming entries.
+    /// A cached lookup is still valid as long as the epoch stays the same.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub(crate) fn bump_epoch(&mut self) {
+        self.epoch += 1;
+        let epoch = self.epoch;
+        self.modify_super_block(|super_block| super_block.epoch = epoch);
     }
     /*
     alloc_data 和 dealloc_data 分配/回收数据块传入/返回的
     参数都表示数据块在块设备上的编号，而不是在数据块位图中分配的 bit 编号
         */
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// How free data blocks are tracked
+    pub fn data_allocator(&self) -> DataAllocator {
+        match self.free_list {
+            Some(_) => DataAllocator::FreeList,
+            None => DataAllocator::Bitmap,
+        }
     }
 
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
+    // 把空闲链表的状态写回超级块
+    fn save_free_list(&self) {
+        if let Some((head, len)) = self.free_list {
+            self.modify_super_block(|super_block| {
+                super_block.flags |= SB_FLAG_FREE_LIST;
+                super_block.free_list_head = head;
+                super_block.free_list_len = len;
             });
-        self.data_bitmap.dealloc(
-            &self.block_device,
-            (block_id - self.data_area_start_block) as usize,
-        )
+        }
+    }
+
+    /// Allocate a data block, return None if the data area is full
+    pub fn alloc_data(&mut self) -> Option<u32> {
+        check_corrupt(self.try_alloc_data()).ok()
+    }
+
+    /// Same as `alloc_data`, but fail with `EfsError::NoSpace` if the data area is
+    /// full, and with `EfsError::Corrupt` if the free list read from disk is inconsistent
+    pub fn try_alloc_data(&mut self) -> Result<u32, EfsError> {
+        if let Some((head, len)) = self.free_list {
+            if head == 0 {
+                return Err(EfsError::NoSpace);
+            }
+            ensure!(self.data_area().contains(&head), EfsError::Corrupt);
+            // 链表非空而计数为 0 说明计数已损坏
+            let Some(new_len) = len.checked_sub(1) else {
+                return Err(EfsError::Corrupt);
+            };
+            // 取出链表头，并清掉其中的后继指针，使分配出去的块全为 0
+            let next = get_block_cache(head as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |raw: &mut [u8; 4]| {
+                    let next = u32::from_le_bytes(*raw);
+                    *raw = [0u8; 4];
+                    next
+                });
+            self.free_list = Some((next, new_len));
+            self.save_free_list();
+            self.bump_epoch();
+            trace(|| TraceEvent::Alloc { block: head });
+            return Ok(head);
+        }
+        let mut bit = self
+            .data_bitmap
+            .alloc(&self.block_device)
+            .ok_or(EfsError::NoSpace)?;
+        // 位图最后一块中超出数据区的位不对应任何数据块，
+        // 轮转分配时还需要从头再找一遍
+        if bit >= self.data_area_blocks as usize {
+            self.data_bitmap.dealloc(&self.block_device, bit);
+            if !self.data_bitmap.is_rotating() {
+                return Err(EfsError::NoSpace);
+            }
+            self.data_bitmap.rewind();
+            bit = self
+                .data_bitmap
+                .alloc(&self.block_device)
+                .ok_or(EfsError::NoSpace)?;
+            if bit >= self.data_area_blocks as usize {
+                self.data_bitmap.dealloc(&self.block_device, bit);
+                return Err(EfsError::NoSpace);
+            }
+        }
+        self.bump_epoch();
+        let block = bit as u32 + self.data_area_start_block;
+        trace(|| TraceEvent::Alloc { block });
+        Ok(block)
+    }
+
+    /// Allocate `count` data blocks as a whole. If the data area runs out
+    /// partway, the blocks allocated so far are released again and None is returned.
+    pub fn alloc_data_blocks(&mut self, count: usize) -> Option<Vec<u32>> {
+        let mut blocks = Vec::with_capacity(count);
+        for _ in 0..count {
+            match self.alloc_data() {
+                Some(block) => blocks.push(block),
+                None => {
+                    // 逆序释放，空闲链表恢复成分配前的顺序
+                    for &block in blocks.iter().rev() {
+                        assert!(self.dealloc_data(block));
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(blocks)
+    }
+
+    /// Release a data block, return false if it is not an allocated data block.
+    /// With `DataAllocator::FreeList` only blocks outside of the data area are
+    /// detected, releasing a free block again corrupts the list.
+    pub fn dealloc_data(&mut self, block_id: u32) -> bool {
+        self.dealloc_data_blocks(&[block_id])
+    }
+
+    /// Release several data blocks like `dealloc_data`, return false if any of
+    /// them is not an allocated data block; the others are released anyway.
+    /// The zeroed blocks are written back in ascending order and discarded with one
+    /// `BlockDevice::discard` per run of consecutive blocks.
+    pub fn dealloc_data_blocks(&mut self, block_ids: &[u32]) -> bool {
+        let data_area = self.data_area();
+        let mut all_allocated = true;
+        if let Some((mut head, mut len)) = self.free_list {
+            for &block_id in block_ids {
+                if !data_area.contains(&block_id) {
+                    all_allocated = false;
+                    continue;
+                }
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block.iter_mut().for_each(|p| *p = 0);
+                        data_block[..4].copy_from_slice(&head.to_le_bytes());
+                    });
+                // 空闲链表的后继指针保存在块内，链表中的块不能 TRIM
+                (head, len) = (block_id, len + 1);
+                trace(|| TraceEvent::Free { block: block_id });
+            }
+            self.free_list = Some((head, len));
+            self.save_free_list();
+            self.bump_epoch();
+            return all_allocated;
+        }
+        let mut freed = Vec::with_capacity(block_ids.len());
+        for &block_id in block_ids {
+            if block_id < self.data_area_start_block
+                || !self.data_bitmap.dealloc(
+                    &self.block_device,
+                    (block_id - self.data_area_start_block) as usize,
+                )
+            {
+                all_allocated = false;
+                continue;
+            }
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block.iter_mut().for_each(|p| {
+                        *p = 0;
+                    })
+                });
+            trace(|| TraceEvent::Free { block: block_id });
+            freed.push(block_id as usize);
+        }
+        if freed.is_empty() {
+            return all_allocated;
+        }
+        // 先写回清零的块，块缓存变为干净的，之后替换或同步时不会再写这些块覆盖掉 TRIM；
+        // 不保证 TRIM 后读出 0 的设备上空闲块仍然全为 0
+        freed.sort_unstable();
+        sync_blocks(&self.block_device, &freed);
+        let mut start = 0;
+        for end in 1..=freed.len() {
+            if end == freed.len() || freed[end] != freed[end - 1] + 1 {
+                self.block_device.discard(freed[start], end - start);
+                start = end;
+            }
+        }
+        self.bump_epoch();
+        all_allocated
+    }
+
+    /// Number of inodes that can still be allocated
+    pub fn free_inodes(&self) -> usize {
+        self.inode_bitmap.maximum() - self.inode_bitmap.allocated(&self.block_device)
+    }
+
+    /// Block ids of the data area
+    pub(crate) fn data_area(&self) -> Range<u32> {
+        self.data_area_start_block..self.data_area_start_block + self.data_area_blocks
+    }
+
+    // 沿空闲链表收集其中的块，遇到数据区以外的块、环或超过记录的长度时停止
+    fn free_list_blocks(&self) -> BTreeSet<u32> {
+        let mut blocks = BTreeSet::new();
+        let Some((mut block_id, len)) = self.free_list else {
+            return blocks;
+        };
+        while self.data_area().contains(&block_id)
+            && blocks.len() < len as usize
+            && blocks.insert(block_id)
+        {
+            block_id = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |raw: &[u8; 4]| u32::from_le_bytes(*raw));
+        }
+        blocks
+    }
+
+    // 数据区中的块是否已分配，空闲链表模式下查 free_list_blocks 的结果
+    fn is_data_allocated(&self, block_id: u32, free_list: &BTreeSet<u32>) -> bool {
+        match self.free_list {
+            Some(_) => !free_list.contains(&block_id),
+            None => self.data_bitmap.is_allocated(
+                &self.block_device,
+                (block_id - self.data_area_start_block) as usize,
+            ),
+        }
+    }
+
+    /// Blocks recording the allocation of `inode_ids` and `data_blocks`: the superblock,
+    /// which holds the epoch and the head of the free list, and the bitmap blocks of their bits
+    pub(crate) fn allocation_blocks(&self, inode_ids: &[u32], data_blocks: &[u32]) -> Vec<usize> {
+        let mut block_ids = vec![0];
+        block_ids.extend(
+            inode_ids
+                .iter()
+                .map(|&inode_id| self.inode_bitmap.block_of(inode_id as usize)),
+        );
+        if self.free_list.is_none() {
+            block_ids.extend(data_blocks.iter().map(|&block_id| {
+                self.data_bitmap
+                    .block_of((block_id - self.data_area_start_block) as usize)
+            }));
+        }
+        block_ids
+    }
+
+    /// Runs of consecutive free data blocks as `(first block id, length)` in ascending order.
+    /// With `DataAllocator::FreeList` the whole free list is walked.
+    pub fn free_data_ranges(&self) -> Vec<(u32, u32)> {
+        if self.free_list.is_some() {
+            let mut ranges: Vec<(u32, u32)> = Vec::new();
+            for block_id in self.free_list_blocks() {
+                match ranges.last_mut() {
+                    Some((start, len)) if *start + *len == block_id => *len += 1,
+                    _ => ranges.push((block_id, 1)),
+                }
+            }
+            return ranges;
+        }
+        // 位图最后一块中超出数据区的位不对应任何数据块
+        let data_area_blocks = self.data_area_blocks as usize;
+        self.data_bitmap
+            .free_ranges(&self.block_device)
+            .into_iter()
+            .filter(|&(start, _)| start < data_area_blocks)
+            .map(|(start, len)| {
+                let len = len.min(data_area_blocks - start);
+                (start as u32 + self.data_area_start_block, len as u32)
+            })
+            .collect()
+    }
+
+    /// Number of data blocks that can still be allocated
+    pub fn free_data_blocks(&self) -> usize {
+        if let Some((_, len)) = self.free_list {
+            return len as usize;
+        }
+        self.data_area_blocks as usize - self.data_bitmap.allocated(&self.block_device)
     }
 }
@@ -0,0 +1,70 @@
+/// Errors reported by easy-fs operations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EfsError {
+    /// The quota of a directory doesn't allow more blocks
+    QuotaExceeded,
+    /// A block read back after being written differs from what was written.
+    /// Operations returning it have been applied, but the block stays dirty
+    WriteVerifyFailed,
+    /// The size of a directory is not a whole number of entries
+    CorruptDir,
+    /// The directory already has an entry of the name
+    AlreadyExists,
+    /// All inodes are in use
+    NoFreeInode,
+    /// The directory has reached the max number of entries
+    DirFull,
+    /// The inode of a handle has been released and reallocated
+    Stale,
+    /// The block device failed to read or write a block
+    DeviceError,
+    /// There are not enough free data blocks
+    NoSpace,
+    /// The superblock doesn't have the magic number of easy-fs
+    InvalidSuperBlock,
+    /// The block device has fewer blocks than the superblock claims
+    DeviceTooSmall,
+    /// The inode is not a directory
+    NotDir,
+    /// No entry of the name or path exists
+    NotFound,
+    /// The inode is a directory, where a regular file is required
+    IsDir,
+    /// A directory can't be moved or copied into its own subtree
+    IntoSubtree,
+    /// The block cache can't hold the blocks an operation keeps in use at the same time
+    CacheTooSmall,
+    /// The inode is immutable and can't be written, truncated or unlinked
+    Immutable,
+    /// Inode 0 is not an allocated directory, so there is no root to resolve paths from
+    NoRoot,
+    /// The name is empty, `.` or `..`, too long for a directory entry or contains `/` or NUL
+    InvalidName,
+    /// A block pointer read from disk lies outside of the data area,
+    /// or the free space accounting on disk is inconsistent
+    Corrupt,
+}
+
+/// Check a condition on data read from disk, return `$err` from current
+/// function if it doesn't hold. Bad on-disk data never panics the `try_`
+/// APIs, internal invariants keep using `assert!`.
+macro_rules! ensure {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            return Err($err);
+        }
+    };
+}
+pub(crate) use ensure;
+
+/// Pass on the result of a `try_` variant to its Option/bool wrapper, which
+/// has no error to report. With the `strict-asserts` feature, corrupt
+/// on-disk data panics there instead of being dropped silently.
+pub(crate) fn check_corrupt<T>(result: Result<T, EfsError>) -> Result<T, EfsError> {
+    if cfg!(feature = "strict-asserts") {
+        if let Err(err @ (EfsError::Corrupt | EfsError::CorruptDir)) = result {
+            panic!("Corrupt on-disk data: {:?}", err);
+        }
+    }
+    result
+}
@@ -0,0 +1,43 @@
+// 后台刷盘线程，定期把修改过的块缓存写回块设备，缩小掉电时丢失数据的窗口
+use crate::block_cache::flush_dirty_sorted;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+struct Flusher {
+    handle: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+}
+
+static FLUSHER: Mutex<Option<Flusher>> = Mutex::new(None);
+
+/// Spawn a thread flushing modified block caches every `interval_ms` milliseconds.
+/// A flusher which is already running is stopped first.
+pub fn start_flusher(interval_ms: u64) {
+    stop_flusher();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = Arc::clone(&stop);
+    let handle = thread::spawn(move || {
+        while !thread_stop.load(Ordering::Acquire) {
+            thread::park_timeout(Duration::from_millis(interval_ms));
+            flush_dirty_sorted();
+        }
+    });
+    *FLUSHER.lock().unwrap() = Some(Flusher { handle, stop });
+}
+
+/// Stop the flusher thread and wait for it, flushing one final time
+pub fn stop_flusher() {
+    let flusher = FLUSHER.lock().unwrap().take();
+    if let Some(flusher) = flusher {
+        flusher.stop.store(true, Ordering::Release);
+        flusher.handle.thread().unpark();
+        flusher.handle.join().unwrap();
+    }
+    flush_dirty_sorted();
+}
@@ -1,12 +1,22 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{borrow::Cow, string::String, sync::Arc, vec::Vec};
+use core::ops::Range;
+use spin::Mutex;
 
-use crate::{block_cache::get_block_cache, block_dev::BlockDevice, BLOCK_SZ};
+use crate::{
+    block_cache::{get_block_cache, get_block_cache_zeroed, BlockCache, BLOCK_CACHE_MANAGER},
+    block_dev::BlockDevice,
+    error::{ensure, EfsError},
+    BLOCK_SZ,
+};
 
 /// Magic number for sanity check
 const EFS_MAGIC: u32 = 0x3b800001;
-const INODE_DIRECT_COUNT: usize = 28;
+/// Number of direct block pointers of a DiskInode
+pub const INODE_DIRECT_COUNT: usize = 28;
 
+/// The on-disk superblock in block 0, describing the regions of the filesystem
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -14,18 +24,51 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    // 每个目录最多的目录项数，为 0 表示只受文件大小上限的限制
+    pub max_dir_entries: u32,
+    pub flags: u32,
+    // 空闲链表模式下第一个空闲数据块的块号（0 表示没有空闲块）和空闲块数
+    pub free_list_head: u32,
+    pub free_list_len: u32,
+    // 每次元数据修改加一，供外部缓存判断文件系统是否变化
+    pub epoch: u64,
+    // 用于识别镜像的卷标和 UUID，no_std 下没有随机数，UUID 由调用者给出
+    pub label: [u8; LABEL_LEN],
+    pub uuid: [u8; 16],
 }
+/// Bytes of the label in the superblock
+pub const LABEL_LEN: usize = 32;
+/// Data blocks are allocated from a linked free list instead of the data bitmap
+pub const SB_FLAG_FREE_LIST: u32 = 1;
+/// Names are looked up ignoring ASCII case, and keep their case on disk
+pub const SB_FLAG_CASE_INSENSITIVE: u32 = 2;
+/// New regular files store small contents inline in the inode
+pub const SB_FLAG_INLINE_DATA: u32 = 4;
+/// Metadata blocks carry a type tag, see the `block-tags` feature
+pub const SB_FLAG_BLOCK_TAGS: u32 = 8;
+/// Index blocks address only a few blocks each, see the `small-fanout` feature
+pub const SB_FLAG_SMALL_FANOUT: u32 = 16;
+/// Inodes carry the quota, generation, link count, reservation and flags fields.
+/// Images formatted before that have smaller inodes and are refused
+pub const SB_FLAG_INODE_V2: u32 = 32;
 use core::fmt::Debug;
-use core::fmt::Result;
-use core::{fmt::Formatter, u32};
+use core::fmt::Result as FmtResult;
+use core::fmt::Formatter;
 impl Debug for SuperBlock {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("SuperBlock")
             .field("total_blocks", &self.total_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("max_dir_entries", &self.max_dir_entries)
+            .field("flags", &self.flags)
+            .field("free_list_head", &self.free_list_head)
+            .field("free_list_len", &self.free_list_len)
+            .field("epoch", &self.epoch)
+            .field("label", &self.label)
+            .field("uuid", &self.uuid)
             .finish()
     }
 }
@@ -46,36 +89,208 @@ impl SuperBlock {
             inode_bitmap_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            max_dir_entries: 0,
+            flags: 0,
+            free_list_head: 0,
+            free_list_len: 0,
+            epoch: 0,
+            label: [0; LABEL_LEN],
+            uuid: [0; 16],
         }
     }
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Magic number identifying an easy-fs image
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+    /// Serialize all fields in little-endian order
+    pub fn to_le_bytes(&self) -> [u8; SUPER_BLOCK_SZ] {
+        let mut bytes = [0u8; SUPER_BLOCK_SZ];
+        put_u32(&mut bytes, 0, self.magic);
+        put_u32(&mut bytes, 4, self.total_blocks);
+        put_u32(&mut bytes, 8, self.inode_bitmap_blocks);
+        put_u32(&mut bytes, 12, self.inode_area_blocks);
+        put_u32(&mut bytes, 16, self.data_bitmap_blocks);
+        put_u32(&mut bytes, 20, self.data_area_blocks);
+        put_u32(&mut bytes, 24, self.max_dir_entries);
+        put_u32(&mut bytes, 28, self.flags);
+        put_u32(&mut bytes, 32, self.free_list_head);
+        put_u32(&mut bytes, 36, self.free_list_len);
+        put_u32(&mut bytes, 40, self.epoch as u32);
+        put_u32(&mut bytes, 44, (self.epoch >> 32) as u32);
+        bytes[48..80].copy_from_slice(&self.label);
+        bytes[80..96].copy_from_slice(&self.uuid);
+        bytes
+    }
+    pub fn from_le_bytes(bytes: &[u8; SUPER_BLOCK_SZ]) -> Self {
+        Self {
+            magic: get_u32(bytes, 0),
+            total_blocks: get_u32(bytes, 4),
+            inode_bitmap_blocks: get_u32(bytes, 8),
+            inode_area_blocks: get_u32(bytes, 12),
+            data_bitmap_blocks: get_u32(bytes, 16),
+            data_area_blocks: get_u32(bytes, 20),
+            max_dir_entries: get_u32(bytes, 24),
+            flags: get_u32(bytes, 28),
+            free_list_head: get_u32(bytes, 32),
+            free_list_len: get_u32(bytes, 36),
+            epoch: get_u32(bytes, 40) as u64 | (get_u32(bytes, 44) as u64) << 32,
+            label: bytes[48..80].try_into().unwrap(),
+            uuid: bytes[80..96].try_into().unwrap(),
+        }
+    }
 }
-// 一级索引块中的每个 u32 都用来指向数据块区域中一个保存该文件内容的数据块
-const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+/// Bytes of a serialized SuperBlock
+pub const SUPER_BLOCK_SZ: usize = 96;
+
+// 以小端序读写磁盘上的 u32，使镜像与主机的字节序无关
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(raw)
+}
+// 一级索引块中的每个 u32 都用来指向数据块区域中一个保存该文件内容的数据块，
+// 开启 block-tags 时最后一个 u32 用来保存块类型标签。
+// 开启 small-fanout 时只使用前几个 u32，让测试用很小的文件就能用到二级索引
+const INODE_INDIRECT1_COUNT: usize = if cfg!(feature = "small-fanout") {
+    SMALL_FANOUT
+} else if cfg!(feature = "block-tags") {
+    BLOCK_SZ / 4 - 1
+} else {
+    BLOCK_SZ / 4
+};
+/// Pointers used per index block with the `small-fanout` feature
+pub const SMALL_FANOUT: usize = 8;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 type IndirectBlock = [u32; BLOCK_SZ / 4];
+/// Type tag of index blocks with the `block-tags` feature
+pub const BLOCK_TAG_INDIRECT: u32 = u32::from_le_bytes(*b"EFIX");
+/// Type tag of inode area blocks with the `block-tags` feature
+pub const BLOCK_TAG_INODE: u32 = u32::from_le_bytes(*b"EFIN");
+/// Offset of the type tag in a tagged block
+pub const BLOCK_TAG_OFFSET: usize = BLOCK_SZ - 4;
+// 位图块和目录的数据块不打标签：位图块的每一位都对应一个 inode 或数据块，
+// 目录的数据块恰好放下 BLOCK_SZ / DIRENT_SZ 个目录项，都没有空余的字节。
+// 腾出 4 字节会改变位图能管理的块数，并让目录项按块而不是按偏移量排列
+
+// 把索引块及其下 depth 层中所有的块号加入 out，只进入 valid 成立的索引块
+fn index_tree_blocks(
+    index_block: u32,
+    depth: usize,
+    valid: &impl Fn(u32) -> bool,
+    block_device: &Arc<dyn BlockDevice>,
+    out: &mut Vec<u32>,
+) {
+    if index_block == 0 {
+        return;
+    }
+    out.push(index_block);
+    if depth == 0 || !valid(index_block) {
+        return;
+    }
+    let children: Vec<u32> = get_block_cache(index_block as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |indirect: &IndirectBlock| {
+            indirect[..INODE_INDIRECT1_COUNT].to_vec()
+        });
+    for child in children {
+        index_tree_blocks(child, depth - 1, valid, block_device, out);
+    }
+}
+
+// 清零一个新的索引块，其中的项都是空洞，返回其块号
+fn zeroed_index_block(block_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |indirect: &mut IndirectBlock| indirect.fill(0));
+    stamp_block_tag(block_id as usize, BLOCK_TAG_INDIRECT, block_device);
+    block_id
+}
+
+/// Stamp the type tag of a metadata block if the `block-tags` feature is on
+pub fn stamp_block_tag(block_id: usize, tag: u32, block_device: &Arc<dyn BlockDevice>) {
+    if cfg!(feature = "block-tags") {
+        get_block_cache(block_id, Arc::clone(block_device))
+            .lock()
+            .modify(BLOCK_TAG_OFFSET, |raw: &mut u32| *raw = tag);
+    }
+}
+
+// 新分配的索引块在磁盘上全为 0，直接建立清零的块缓存，不必先从设备读出
+fn new_index_block(block_id: u32, block_device: &Arc<dyn BlockDevice>) -> Arc<Mutex<BlockCache>> {
+    let block_cache = get_block_cache_zeroed(block_id as usize, Arc::clone(block_device));
+    stamp_block_tag(block_id as usize, BLOCK_TAG_INDIRECT, block_device);
+    block_cache
+}
+
+/// Check the type tag of a metadata block being read, in debug builds with the
+/// `block-tags` feature. A mismatch means a block pointer points at the wrong kind of block.
+pub fn check_block_tag(found: u32, expected: u32) {
+    if cfg!(debug_assertions) {
+        assert!(
+            block_tag_matches(found, expected),
+            "Block tag {:#x} found, {:#x} expected!",
+            found,
+            expected
+        );
+    }
+}
+
+/// Whether a block tag is the expected one, always true without the `block-tags` feature
+pub fn block_tag_matches(found: u32, expected: u32) -> bool {
+    !cfg!(feature = "block-tags") || found == expected
+}
+
+fn check_indirect(indirect: &IndirectBlock) {
+    check_block_tag(indirect[BLOCK_TAG_OFFSET / 4], BLOCK_TAG_INDIRECT);
+}
 
 #[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     pub size: u32,                       // 文件或者目录的字节数
     pub direct: [u32; INODE_DIRECT_COUNT], // 28 * 0.5KiB = 14KiB
     pub indirect1: u32,                    // 128 * 0.5KiB = 64KiB
     pub indirect2: u32,                    // 128 * 64KiB = 8MiB
+    pub quota: u32,                        // 目录及其直接子文件可占用的块数上限，0 表示不限制
+    pub generation: u32,                   // 该 inode 编号被分配的次数，用于识别过期的句柄
+    pub nlink: u32,                        // 指向该 inode 的目录项数，含目录的 . 和子目录的 ..
+    pub reserved: u32,                     // 预留的字节数，超出 size 的部分也已分配数据块
+    pub flags: u32,                        // INODE_FLAG_*
     type_: DiskInodeType,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
 }
 
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The max size of a file that direct and indirect blocks can address
+pub const MAX_FILE_SIZE: usize = INDIRECT2_BOUND * BLOCK_SZ;
+/// The content of the inode is stored in place of its direct block pointers
+pub const INODE_FLAG_INLINE: u32 = 1;
+/// The inode can't be written, truncated or unlinked
+pub const INODE_FLAG_IMMUTABLE: u32 = 2;
+/// Max bytes of inline content
+pub const INLINE_DATA_SZ: usize = INODE_DIRECT_COUNT * 4;
+/// Bytes of a DiskInode on disk, the same as its in-memory size
+pub const DISK_INODE_SZ: usize = core::mem::size_of::<DiskInode>();
+/// Disk inodes packed into one inode area block, derived from the size of
+/// `DiskInode` so that inodes never straddle two blocks
+pub const INODES_PER_BLOCK: usize = BLOCK_SZ / DISK_INODE_SZ;
+const _: () = assert!(INODES_PER_BLOCK > 0);
+// 块类型标签放在 inode 块末尾没有被 DiskInode 占用的字节中
+const _: () =
+    assert!(!cfg!(feature = "block-tags") || INODES_PER_BLOCK * DISK_INODE_SZ <= BLOCK_TAG_OFFSET);
 
 impl DiskInode {
     /// Initialize a disk inode, as well as all direct inodes under it
@@ -85,14 +300,90 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.quota = 0;
+        self.generation = self.generation.wrapping_add(1);
+        self.reserved = 0;
+        self.flags = 0;
+        self.nlink = match type_ {
+            DiskInodeType::File => 1,
+            DiskInodeType::Directory => 2,
+        };
         self.type_ = type_;
     }
+    /// Deserialize a disk inode, return None if its type is unknown
+    pub fn from_le_bytes(bytes: &[u8; DISK_INODE_SZ]) -> Option<Self> {
+        let offset = 4 + INODE_DIRECT_COUNT * 4;
+        let type_ = match bytes[offset + 28] {
+            0 => DiskInodeType::File,
+            1 => DiskInodeType::Directory,
+            _ => return None,
+        };
+        let mut direct = [0u32; INODE_DIRECT_COUNT];
+        for (i, block_id) in direct.iter_mut().enumerate() {
+            *block_id = get_u32(bytes, 4 + i * 4);
+        }
+        Some(Self {
+            size: get_u32(bytes, 0),
+            direct,
+            indirect1: get_u32(bytes, offset),
+            indirect2: get_u32(bytes, offset + 4),
+            quota: get_u32(bytes, offset + 8),
+            generation: get_u32(bytes, offset + 12),
+            nlink: get_u32(bytes, offset + 16),
+            reserved: get_u32(bytes, offset + 20),
+            flags: get_u32(bytes, offset + 24),
+            type_,
+        })
+    }
+    /// Fill an empty directory with the `.` and `..` entries, `new_blocks`
+    /// are the data blocks needed by the size of the two entries
+    pub fn init_dot_entries(
+        &mut self,
+        inode_id: u32,
+        parent_id: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert!(self.is_dir() && self.size == 0);
+        self.increase_size(2 * DIRENT_SZ as u32, new_blocks, block_device);
+        self.write_at(0, DirEntry::new(".", inode_id).as_bytes(), block_device);
+        self.write_at(
+            DIRENT_SZ,
+            DirEntry::new("..", parent_id).as_bytes(),
+            block_device,
+        );
+    }
+    /// Whether the content is stored inline
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+    /// Store the content of an empty regular file inline from now on
+    pub fn set_inline(&mut self) {
+        assert!(self.is_file() && self.size == 0 && self.reserved == 0);
+        self.flags |= INODE_FLAG_INLINE;
+    }
+    /// Whether the inode is protected from writes, truncation and unlinking
+    pub fn is_immutable(&self) -> bool {
+        self.flags & INODE_FLAG_IMMUTABLE != 0
+    }
+    pub fn set_immutable(&mut self, immutable: bool) {
+        if immutable {
+            self.flags |= INODE_FLAG_IMMUTABLE;
+        } else {
+            self.flags &= !INODE_FLAG_IMMUTABLE;
+        }
+    }
+    fn inline_data(&self) -> &[u8; INLINE_DATA_SZ] {
+        unsafe { &*(self.direct.as_ptr() as *const [u8; INLINE_DATA_SZ]) }
+    }
+    fn inline_data_mut(&mut self) -> &mut [u8; INLINE_DATA_SZ] {
+        unsafe { &mut *(self.direct.as_mut_ptr() as *mut [u8; INLINE_DATA_SZ]) }
+    }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
     }
     /// Whether this inode is a file
-    #[allow(unused)]
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
@@ -105,6 +396,7 @@ impl DiskInode {
             get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
                 .lock()
                 .read(0, |indirect_block: &IndirectBlock| {
+                    check_indirect(indirect_block);
                     indirect_block[inner_id - INODE_DIRECT_COUNT]
                 })
         } else {
@@ -112,42 +404,243 @@ impl DiskInode {
             let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
                 .lock()
                 .read(0, |indirect2: &IndirectBlock| {
+                    check_indirect(indirect2);
                     indirect2[last / INODE_INDIRECT1_COUNT]
                 });
             get_block_cache(indirect1 as usize, Arc::clone(block_device))
                 .lock()
                 .read(0, |indirect1: &IndirectBlock| {
+                    check_indirect(indirect1);
                     indirect1[last % INODE_INDIRECT1_COUNT]
                 })
         }
     }
 
+    /// Same as `get_block_id`, but check that the index blocks followed and the block
+    /// id found lie in `data_area`, so that a corrupt pointer can't make the cache
+    /// read an arbitrary block. A hole is fine, an index block can't be one.
+    pub fn try_get_block_id(
+        &self,
+        inner_id: u32,
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<u32, EfsError> {
+        let check = |block_id: u32| {
+            ensure!(
+                block_id == 0 || data_area.contains(&block_id),
+                EfsError::Corrupt
+            );
+            Ok(block_id)
+        };
+        let follow = |index_block: u32, idx: usize| {
+            ensure!(data_area.contains(&index_block), EfsError::Corrupt);
+            let (tag, block_id) = get_block_cache(index_block as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| {
+                    (indirect_block[BLOCK_TAG_OFFSET / 4], indirect_block[idx])
+                });
+            ensure!(
+                block_tag_matches(tag, BLOCK_TAG_INDIRECT),
+                EfsError::Corrupt
+            );
+            check(block_id)
+        };
+        let inner_id = inner_id as usize;
+        // 超出二级索引范围的块号只能来自损坏的 size
+        ensure!(inner_id < INDIRECT2_BOUND, EfsError::Corrupt);
+        if inner_id < INODE_DIRECT_COUNT {
+            check(self.direct[inner_id])
+        } else if inner_id < INDIRECT1_BOUND {
+            follow(self.indirect1, inner_id - INODE_DIRECT_COUNT)
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = follow(self.indirect2, last / INODE_INDIRECT1_COUNT)?;
+            follow(indirect1, last % INODE_INDIRECT1_COUNT)
+        }
+    }
+
+    /// Map a data block covered by the mapped size to `block_id`, 0 makes it a hole
+    pub fn set_block_id(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+            return;
+        }
+        let (indirect1, idx) = if inner_id < INDIRECT1_BOUND {
+            (self.indirect1, inner_id - INODE_DIRECT_COUNT)
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    check_indirect(indirect2);
+                    indirect2[last / INODE_INDIRECT1_COUNT]
+                });
+            (indirect1, last % INODE_INDIRECT1_COUNT)
+        };
+        get_block_cache(indirect1 as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect1: &mut IndirectBlock| {
+                check_indirect(indirect1);
+                indirect1[idx] = block_id;
+            });
+    }
+
+    /// Ids of all allocated data blocks in file order, excluding index blocks and holes
+    pub fn data_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v = Vec::new();
+        self.walk_mapping(
+            |_| true,
+            block_device,
+            |block_id, is_index| {
+                if !is_index {
+                    v.push(block_id);
+                }
+            },
+        );
+        v
+    }
+
+    /// Inner ids of the holes among the mapped data blocks covering `[start, end)`.
+    /// Fail with `EfsError::Corrupt` if a block pointer lies outside of `data_area`.
+    pub fn holes(
+        &self,
+        start: usize,
+        end: usize,
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<u32>, EfsError> {
+        let end = end.min(self.mapped_size() as usize);
+        let mut holes = Vec::new();
+        for inner_id in (start / BLOCK_SZ..end.div_ceil(BLOCK_SZ)).map(|inner_id| inner_id as u32) {
+            if self.try_get_block_id(inner_id, data_area, block_device)? == 0 {
+                holes.push(inner_id);
+            }
+        }
+        Ok(holes)
+    }
+
+    /// Zero `[start, end)` within the size and unmap the data blocks entirely
+    /// inside it, leaving holes that read back as zeros. Return the unmapped blocks.
+    pub fn punch_hole(
+        &mut self,
+        start: usize,
+        end: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let end = end.min(self.size as usize);
+        if start >= end {
+            return Vec::new();
+        }
+        if self.is_inline() {
+            self.inline_data_mut()[start..end].fill(0);
+            return Vec::new();
+        }
+        let first = start.div_ceil(BLOCK_SZ);
+        let last = (end / BLOCK_SZ).max(first);
+        // 首尾不完整的块只清零
+        self.zero_range(start, end.min(first * BLOCK_SZ), block_device);
+        self.zero_range(start.max(last * BLOCK_SZ), end, block_device);
+        (first as u32..last as u32)
+            .filter_map(|inner_id| {
+                let block_id = self.get_block_id(inner_id, block_device);
+                if block_id == 0 {
+                    return None;
+                }
+                self.set_block_id(inner_id, 0, block_device);
+                Some(block_id)
+            })
+            .collect()
+    }
+
+    fn zero_range(&self, mut start: usize, end: usize, block_device: &Arc<dyn BlockDevice>) {
+        while start < end {
+            let end_current_block = ((start / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+            let block_id = self.get_block_id((start / BLOCK_SZ) as u32, block_device);
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block[start % BLOCK_SZ..start % BLOCK_SZ + end_current_block - start]
+                            .fill(0);
+                    });
+            }
+            start = end_current_block;
+        }
+    }
+
+    /// Ids of all allocated index blocks: indirect1, indirect2 and
+    /// the low-level indirect1 blocks under indirect2
+    pub fn index_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v = Vec::new();
+        self.walk_mapping(
+            |_| true,
+            block_device,
+            |block_id, is_index| {
+                if is_index {
+                    v.push(block_id);
+                }
+            },
+        );
+        v
+    }
+
+    /// Number of data blocks mapped by the inode, including the reserved ones
     pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.size)
+        Self::_data_blocks(self.mapped_size())
+    }
+
+    /// Bytes covered by the mapped data blocks
+    pub fn mapped_size(&self) -> u32 {
+        if self.is_inline() {
+            return 0;
+        }
+        self.size.max(self.reserved)
+    }
+
+    /// Number of data and index blocks mapped by the inode
+    pub fn mapped_blocks(&self) -> u32 {
+        Self::total_blocks(self.mapped_size())
     }
 
     fn _data_blocks(size: u32) -> u32 {
         // 向上取整
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+        size.div_ceil(BLOCK_SZ as u32)
     }
 
     pub fn total_blocks(size: u32) -> u32 {
         let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
+        let mut total = data_blocks;
         if data_blocks > INODE_DIRECT_COUNT {
             total += 1;
         }
         if data_blocks > INDIRECT1_BOUND {
             total += 1;
             total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+                (data_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
         }
         total as u32
     }
+    /// Levels of index blocks above the data blocks of a file with `data_blocks` blocks
+    pub fn index_depth(data_blocks: u32) -> usize {
+        match data_blocks as usize {
+            blocks if blocks > INDIRECT1_BOUND => 2,
+            blocks if blocks > INODE_DIRECT_COUNT => 1,
+            _ => 0,
+        }
+    }
     // 计算扩展所需的字节数
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+        if self.is_inline() && new_size as usize <= INLINE_DATA_SZ {
+            return 0;
+        }
+        Self::total_blocks(new_size).saturating_sub(self.mapped_blocks())
     }
 
     pub fn increase_size(
@@ -158,6 +651,21 @@ impl DiskInode {
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
+        if self.is_inline() {
+            if new_size as usize <= INLINE_DATA_SZ {
+                self.size = new_size;
+                return;
+            }
+            // 内联内容放不下了，转存到数据块中
+            let inline_data = *self.inline_data();
+            let inline_size = self.size as usize;
+            self.flags &= !INODE_FLAG_INLINE;
+            self.direct = [0; INODE_DIRECT_COUNT];
+            self.size = 0;
+            self.increase_size(new_size, new_blocks, block_device);
+            self.write_at(0, &inline_data[..inline_size], block_device);
+            return;
+        }
         let mut current_blocks = self.data_blocks();
         self.size = new_size;
         let mut total_blocks = self.data_blocks();
@@ -168,69 +676,355 @@ impl DiskInode {
             current_blocks += 1;
         }
         // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
+        let indirect1 = if total_blocks > INODE_DIRECT_COUNT as u32 {
+            let indirect1 = if current_blocks == INODE_DIRECT_COUNT as u32 {
                 self.indirect1 = new_blocks.next().unwrap();
-            }
+                new_index_block(self.indirect1, block_device)
+            } else {
+                get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+            };
             current_blocks -= INODE_DIRECT_COUNT as u32;
             total_blocks -= INODE_DIRECT_COUNT as u32;
+            indirect1
         } else {
             return;
-        }
+        };
         // fill indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
-                }
-            });
+        indirect1.lock().modify(0, |indirect1: &mut IndirectBlock| {
+            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
+                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
+                current_blocks += 1;
+            }
+        });
+        // 不再占用一级索引块的缓存，块缓存数刚好够用时二级索引块仍能载入
+        drop(indirect1);
         // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
+        let indirect2 = if total_blocks > INODE_INDIRECT1_COUNT as u32 {
+            let indirect2 = if current_blocks == INODE_INDIRECT1_COUNT as u32 {
                 self.indirect2 = new_blocks.next().unwrap();
-            }
+                new_index_block(self.indirect2, block_device)
+            } else {
+                get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+            };
             current_blocks -= INODE_INDIRECT1_COUNT as u32;
             total_blocks -= INODE_INDIRECT1_COUNT as u32;
+            indirect2
         } else {
             return;
-        }
+        };
         // fill indirect2 from (a0, b0) -> (a1, b1)
         let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
         let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
         let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
         let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
         // alloc low-level indirect1
+        indirect2.lock().modify(0, |indirect2: &mut IndirectBlock| {
+            while (a0 < a1) || (a0 == a1 && b0 < b1) {
+                let indirect1 = if b0 == 0 {
+                    indirect2[a0] = new_blocks.next().unwrap();
+                    new_index_block(indirect2[a0], block_device)
+                } else {
+                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
+                };
+                // fill current
+                indirect1.lock().modify(0, |indirect1: &mut IndirectBlock| {
+                    indirect1[b0] = new_blocks.next().unwrap();
+                });
+                // move to next
+                b0 += 1;
+                if b0 == INODE_INDIRECT1_COUNT {
+                    b0 = 0;
+                    a0 += 1;
+                }
+            }
+        });
+    }
+    /// Ids of the data and index blocks mapped up to the mapped size, holes excluded.
+    /// An index block is only read if `valid` holds for it, so that a corrupted
+    /// inode can be checked without reading arbitrary blocks.
+    pub fn mapped_block_ids(
+        &self,
+        valid: impl Fn(u32) -> bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let mut v = Vec::new();
+        self.walk_mapping(valid, block_device, |block_id, _| v.push(block_id));
+        v
+    }
+
+    /// Visit the data and index blocks mapped up to the mapped size, an index block
+    /// before the blocks it maps and data blocks in file order, holes excluded.
+    /// `f` gets the block id and whether it is an index block. An index block is
+    /// only read if `valid` holds for it.
+    pub fn walk_mapping(
+        &self,
+        valid: impl Fn(u32) -> bool,
+        block_device: &Arc<dyn BlockDevice>,
+        mut f: impl FnMut(u32, bool),
+    ) {
+        if self.is_inline() {
+            return;
+        }
+        let blocks = self.data_blocks() as usize;
+        let mut visit = |block_id: u32, is_index: bool| {
+            if block_id != 0 {
+                f(block_id, is_index);
+            }
+        };
+        let read_index = |block_id: u32, count: usize| -> Vec<u32> {
+            if block_id == 0 || !valid(block_id) {
+                return Vec::new();
+            }
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect: &IndirectBlock| {
+                    check_indirect(indirect);
+                    indirect[..count].to_vec()
+                })
+        };
+        for &block_id in self.direct[..blocks.min(INODE_DIRECT_COUNT)].iter() {
+            visit(block_id, false);
+        }
+        if blocks > INODE_DIRECT_COUNT {
+            visit(self.indirect1, true);
+            let count = (blocks - INODE_DIRECT_COUNT).min(INODE_INDIRECT1_COUNT);
+            for block_id in read_index(self.indirect1, count) {
+                visit(block_id, false);
+            }
+        }
+        if blocks > INDIRECT1_BOUND {
+            visit(self.indirect2, true);
+            let rest = blocks - INDIRECT1_BOUND;
+            let indirect1s = read_index(self.indirect2, rest.div_ceil(INODE_INDIRECT1_COUNT));
+            for (a, indirect1) in indirect1s.into_iter().enumerate() {
+                visit(indirect1, true);
+                let count = (rest - a * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                for block_id in read_index(indirect1, count) {
+                    visit(block_id, false);
+                }
+            }
+        }
+    }
+
+    /// Compare the block mapping with the size: return the number of index
+    /// blocks the size needs but which are not mapped, and whether slots of the
+    /// disk inode past the size still hold block ids
+    pub fn mapping_mismatch(&self, block_device: &Arc<dyn BlockDevice>) -> (usize, bool) {
+        if self.is_inline() {
+            return (0, false);
+        }
+        let blocks = self.data_blocks() as usize;
+        let extra = self.direct[blocks.min(INODE_DIRECT_COUNT)..]
+            .iter()
+            .any(|&block_id| block_id != 0)
+            || (blocks <= INODE_DIRECT_COUNT && self.indirect1 != 0)
+            || (blocks <= INDIRECT1_BOUND && self.indirect2 != 0);
+        let mut missing = 0;
+        if blocks > INODE_DIRECT_COUNT && self.indirect1 == 0 {
+            missing += 1;
+        }
+        if blocks > INDIRECT1_BOUND {
+            let indirect1_count = (blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            missing += if self.indirect2 == 0 {
+                1 + indirect1_count
+            } else {
+                get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |indirect2: &IndirectBlock| {
+                        check_indirect(indirect2);
+                        indirect2[..indirect1_count]
+                            .iter()
+                            .filter(|&&block_id| block_id == 0)
+                            .count()
+                    })
+            };
+        }
+        (missing, extra)
+    }
+
+    /// Make the block mapping agree with the size: map the missing index blocks
+    /// to `new_blocks`, as many as `mapping_mismatch` reports, and clear the slots
+    /// past the size. New index blocks are zeroed, so the data under them reads as
+    /// holes. Return the blocks of the cleared slots and, below cleared index blocks
+    /// for which `valid` holds, the blocks they map. They are not freed here,
+    /// another inode may use them.
+    pub fn fit_mapping_to_size(
+        &mut self,
+        new_blocks: Vec<u32>,
+        valid: impl Fn(u32) -> bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        let blocks = self.data_blocks() as usize;
+        let mut new_blocks = new_blocks.into_iter();
+        let mut cleared: Vec<u32> = self.direct[blocks.min(INODE_DIRECT_COUNT)..]
+            .iter()
+            .copied()
+            .filter(|&block_id| block_id != 0)
+            .collect();
+        self.direct[blocks.min(INODE_DIRECT_COUNT)..].fill(0);
+        if blocks <= INODE_DIRECT_COUNT {
+            index_tree_blocks(self.indirect1, 1, &valid, block_device, &mut cleared);
+            self.indirect1 = 0;
+        } else if self.indirect1 == 0 {
+            self.indirect1 = zeroed_index_block(new_blocks.next().unwrap(), block_device);
+        }
+        if blocks <= INDIRECT1_BOUND {
+            index_tree_blocks(self.indirect2, 2, &valid, block_device, &mut cleared);
+            self.indirect2 = 0;
+            return cleared;
+        }
+        if self.indirect2 == 0 {
+            self.indirect2 = zeroed_index_block(new_blocks.next().unwrap(), block_device);
+        }
+        let indirect1_count = (blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
         get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
             .lock()
             .modify(0, |indirect2: &mut IndirectBlock| {
-                while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        indirect2[a0] = new_blocks.next().unwrap();
-                    }
-                    // fill current
-                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            indirect1[b0] = new_blocks.next().unwrap();
-                        });
-                    // move to next
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
+                for slot in indirect2[..indirect1_count].iter_mut() {
+                    if *slot == 0 {
+                        *slot = zeroed_index_block(new_blocks.next().unwrap(), block_device);
                     }
                 }
             });
+        cleared
+    }
+
+    /// Inner id of the last data block mapped to a block for which `valid` holds,
+    /// following only mapped index blocks which are valid themselves.
+    /// Slots past the size are searched too, as they may be left by a crash.
+    pub fn last_mapped_block(
+        &self,
+        valid: impl Fn(u32) -> bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<u32> {
+        if self.is_inline() {
+            return None;
+        }
+        let last_in = |index_block: u32| {
+            get_block_cache(index_block as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect: &IndirectBlock| {
+                    check_indirect(indirect);
+                    indirect[..INODE_INDIRECT1_COUNT]
+                        .iter()
+                        .rposition(|&block_id| block_id != 0 && valid(block_id))
+                })
+        };
+        if self.indirect2 != 0 && valid(self.indirect2) {
+            let indirect2 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    check_indirect(indirect2);
+                    *indirect2
+                });
+            for (a, &indirect1) in indirect2[..INODE_INDIRECT1_COUNT].iter().enumerate().rev() {
+                if indirect1 == 0 || !valid(indirect1) {
+                    continue;
+                }
+                if let Some(b) = last_in(indirect1) {
+                    return Some((INDIRECT1_BOUND + a * INODE_INDIRECT1_COUNT + b) as u32);
+                }
+            }
+        }
+        if self.indirect1 != 0 && valid(self.indirect1) {
+            if let Some(b) = last_in(self.indirect1) {
+                return Some((INODE_DIRECT_COUNT + b) as u32);
+            }
+        }
+        self.direct
+            .iter()
+            .rposition(|&block_id| block_id != 0 && valid(block_id))
+            .map(|inner_id| inner_id as u32)
+    }
+    /// Map data blocks up to `new_reserved` bytes without changing the size,
+    /// `new_blocks` are the blocks needed as given by `blocks_num_needed`
+    pub fn reserve(
+        &mut self,
+        new_reserved: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert!(new_reserved > self.mapped_size());
+        let size = self.size;
+        self.increase_size(new_reserved, new_blocks, block_device);
+        self.size = size;
+        self.reserved = new_reserved;
+    }
+    /// Shrink the size of a disk inode and drop its reservation,
+    /// return the data and index blocks that are no longer needed
+    pub fn decrease_size(&mut self, new_size: u32, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        if self.is_inline() {
+            // 清掉截断的内容，以免之后扩展时重新出现
+            self.inline_data_mut()[new_size as usize..].fill(0);
+            self.size = new_size;
+            return Vec::new();
+        }
+        let v = self.blocks_released_by(new_size, block_device);
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        // direct
+        for i in new_blocks.min(INODE_DIRECT_COUNT)..old_blocks.min(INODE_DIRECT_COUNT) {
+            self.direct[i] = 0;
+        }
+        if old_blocks > INDIRECT1_BOUND && new_blocks <= INDIRECT1_BOUND {
+            self.indirect2 = 0;
+        }
+        if old_blocks > INODE_DIRECT_COUNT && new_blocks <= INODE_DIRECT_COUNT {
+            self.indirect1 = 0;
+        }
+        self.size = new_size;
+        self.reserved = 0;
+        v
+    }
+
+    /// The data and index blocks `decrease_size(new_size)` would release, without changing anything
+    pub fn blocks_released_by(
+        &self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        if self.is_inline() {
+            return Vec::new();
+        }
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        let mut v: Vec<u32> = (new_blocks..old_blocks)
+            .map(|inner_id| self.get_block_id(inner_id as u32, block_device))
+            .filter(|block_id| *block_id != 0)
+            .collect();
+        // low-level indirect1 blocks under indirect2
+        if old_blocks > INDIRECT1_BOUND {
+            let old_indirect1 = (old_blocks - INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            let new_indirect1 =
+                new_blocks.saturating_sub(INDIRECT1_BOUND).div_ceil(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    v.extend_from_slice(&indirect2[new_indirect1..old_indirect1]);
+                });
+            if new_indirect1 == 0 {
+                v.push(self.indirect2);
+            }
+        }
+        // indirect1
+        if old_blocks > INODE_DIRECT_COUNT && new_blocks <= INODE_DIRECT_COUNT {
+            v.push(self.indirect1);
+        }
+        v
     }
-    /// The max number of indirect2 inodes
 
     // 将回收的所有块的编号保存在一个向量中返回给磁盘块管理器
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
+        if self.is_inline() {
+            self.inline_data_mut().fill(0);
+            self.size = 0;
+            return v;
+        }
         let mut data_blocks = self.data_blocks() as usize;
         self.size = 0;
+        self.reserved = 0;
         let mut current_blocks = 0usize;
         // direct
         while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
@@ -277,7 +1071,7 @@ impl DiskInode {
                     get_block_cache(*entry as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter() {
+                            for entry in indirect1.iter().take(INODE_INDIRECT1_COUNT) {
                                 v.push(*entry);
                             }
                         });
@@ -296,6 +1090,8 @@ impl DiskInode {
                 }
             });
         self.indirect2 = 0;
+        // 空洞不对应数据块
+        v.retain(|block_id| *block_id != 0);
         v
     }
 }
@@ -303,6 +1099,16 @@ impl DiskInode {
 // 数据块
 type DataBlock = [u8; BLOCK_SZ];
 
+// 直接读写时一段连续字节的去处
+#[derive(Clone, Copy)]
+enum Extent {
+    Hole,
+    // 不足一块或已经在缓存中的块，经过块缓存读写
+    Cached(u32),
+    // 从这个块开始的若干个物理上连续的整块，绕过块缓存
+    Direct(u32),
+}
+
 impl DiskInode {
     pub fn read_at(
         &self,
@@ -310,10 +1116,58 @@ impl DiskInode {
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
+        self.read_mapped(offset, buf, block_device, |inner_id| {
+            Ok(self.get_block_id(inner_id, block_device))
+        })
+        .unwrap_or(0)
+    }
+
+    /// Same as `read_at`, but fail with `EfsError::Corrupt` if a block pointer
+    /// followed lies outside of `data_area`, see `try_get_block_id`
+    pub fn try_read_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize, EfsError> {
+        self.read_mapped(offset, buf, block_device, |inner_id| {
+            self.try_get_block_id(inner_id, data_area, block_device)
+        })
+    }
+
+    // 按 block_id 给出的映射读取，它失败时不再继续读
+    fn read_mapped(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_id: impl Fn(u32) -> Result<u32, EfsError>,
+    ) -> Result<usize, EfsError> {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
+        let end = offset
+            .saturating_add(buf.len())
+            .min(self.size as usize)
+            .min(MAX_FILE_SIZE);
         if start >= end {
-            return 0;
+            return Ok(0);
+        }
+        if self.is_inline() {
+            buf[..end - start].copy_from_slice(&self.inline_data()[start..end]);
+            return Ok(end - start);
+        }
+        // 只有一个数据块的文件直接读 direct[0]，不走按块循环
+        if self.size as usize <= BLOCK_SZ {
+            let dst = &mut buf[..end - start];
+            match block_id(0)? {
+                0 => dst.fill(0),
+                block_id => get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        dst.copy_from_slice(&data_block[start..end])
+                    }),
+            }
+            return Ok(end - start);
         }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
@@ -322,15 +1176,16 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            match block_id(start_block as u32)? {
+                // 空洞读出来是 0
+                0 => dst.fill(0),
+                block_id => get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                        dst.copy_from_slice(src);
+                    }),
+            }
             read_size += block_read_size;
             if end_current_block == end {
                 break;
@@ -338,18 +1193,120 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        read_size
+        Ok(read_size)
     }
 
-    pub fn write_at(
+    /// Same as `try_read_at`, but fill the segments of `bufs` one after another.
+    /// Each block is read once, even if it straddles two segments.
+    pub fn read_at_scatter(
         &self,
         offset: usize,
+        bufs: &mut [&mut [u8]],
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize, EfsError> {
+        let total: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let mut start = offset;
+        let end = offset
+            .saturating_add(total)
+            .min(self.size as usize)
+            .min(MAX_FILE_SIZE);
+        if start >= end {
+            return Ok(0);
+        }
+        // 正在填充的分段，以及其中已经填充的字节数
+        let (mut seg, mut seg_pos) = (0usize, 0usize);
+        let mut scatter = |mut src: &[u8]| {
+            while !src.is_empty() {
+                let dst = &mut bufs[seg][seg_pos..];
+                let n = dst.len().min(src.len());
+                dst[..n].copy_from_slice(&src[..n]);
+                src = &src[n..];
+                seg_pos += n;
+                if seg_pos == bufs[seg].len() {
+                    seg += 1;
+                    seg_pos = 0;
+                }
+            }
+        };
+        if self.is_inline() {
+            scatter(&self.inline_data()[start..end]);
+            return Ok(end - start);
+        }
+        while start < end {
+            let end_current_block = ((start / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+            let range = start % BLOCK_SZ..start % BLOCK_SZ + end_current_block - start;
+            match self.try_get_block_id((start / BLOCK_SZ) as u32, data_area, block_device)? {
+                0 => scatter(&[0u8; BLOCK_SZ][range]),
+                block_id => get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| scatter(&data_block[range])),
+            }
+            start = end_current_block;
+        }
+        Ok(end - offset)
+    }
+
+    pub fn write_at(
+        &mut self,
+        offset: usize,
         buf: &[u8],
         block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
+        self.write_mapped(offset, buf, block_device, |disk_inode, inner_id| {
+            Ok(disk_inode.get_block_id(inner_id, block_device))
+        })
+        .unwrap_or(0)
+    }
+
+    /// Same as `write_at`, but fail with `EfsError::Corrupt` if a block pointer
+    /// followed lies outside of `data_area`, see `try_get_block_id`
+    pub fn try_write_at(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize, EfsError> {
+        self.write_mapped(offset, buf, block_device, |disk_inode, inner_id| {
+            disk_inode.try_get_block_id(inner_id, data_area, block_device)
+        })
+    }
+
+    // 按 block_id 给出的映射写入，它失败时不再继续写
+    fn write_mapped(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_id: impl Fn(&Self, u32) -> Result<u32, EfsError>,
+    ) -> Result<usize, EfsError> {
         let mut start = offset;
-        let end = (offset + buf.len()).min(self.size as usize);
-        assert!(start <= end);
+        let end = offset
+            .saturating_add(buf.len())
+            .min(self.size as usize)
+            .min(MAX_FILE_SIZE);
+        if start >= end {
+            return Ok(0);
+        }
+        if self.is_inline() {
+            self.inline_data_mut()[start..end].copy_from_slice(&buf[..end - start]);
+            return Ok(end - start);
+        }
+        if self.size as usize <= BLOCK_SZ {
+            let block_id = block_id(self, 0)? as usize;
+            assert_ne!(block_id, 0);
+            // 整块覆盖时不读设备，清零的块缓存一开始就是已修改的，写入全 0 也会写回
+            let block_cache = if end - start == BLOCK_SZ {
+                get_block_cache_zeroed(block_id, Arc::clone(block_device))
+            } else {
+                get_block_cache(block_id, Arc::clone(block_device))
+            };
+            block_cache.lock().modify(0, |data_block: &mut DataBlock| {
+                data_block[start..end].copy_from_slice(&buf[..end - start]);
+            });
+            return Ok(end - start);
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
         loop {
@@ -358,16 +1315,23 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = block_id(self, start_block as u32)? as usize;
+            // 空洞须先由调用者分配数据块，否则会写到 0 号块上
+            assert_ne!(block_id, 0);
+            // a fully overwritten block doesn't need to be read first,
+            // and its zeroed cache starts modified so the write is never skipped
+            let block_cache = if block_write_size == BLOCK_SZ {
+                get_block_cache_zeroed(block_id, Arc::clone(block_device))
+            } else {
+                get_block_cache(block_id, Arc::clone(block_device))
+            };
+            block_cache
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    let src = &buf[write_size..write_size + block_write_size];
+                    let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                    dst.copy_from_slice(src);
+                });
             write_size += block_write_size;
             // move to next block
             if end_current_block == end {
@@ -376,7 +1340,136 @@ impl DiskInode {
             start_block += 1;
             start = end_current_block;
         }
-        write_size
+        Ok(write_size)
+    }
+
+    /// Same as `try_read_at`, but whole data blocks which are not resident are read
+    /// straight from the device into `buf`, consecutive ones with one `read_blocks`,
+    /// without going through the block cache. Partial blocks at both ends and
+    /// resident blocks are read through the cache, which may hold newer data,
+    /// and so are blocks whose part of `buf` doesn't meet `BlockDevice::alignment`.
+    pub fn read_at_direct(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize, EfsError> {
+        if self.is_inline() {
+            return self.try_read_at(offset, buf, data_area, block_device);
+        }
+        let end = offset
+            .saturating_add(buf.len())
+            .min(self.size as usize)
+            .min(MAX_FILE_SIZE);
+        if offset >= end {
+            return Ok(0);
+        }
+        let buf_addr = buf.as_ptr() as usize;
+        for (range, extent) in
+            self.direct_extents(offset, end, buf_addr, data_area, block_device)?
+        {
+            let dst = &mut buf[range.start - offset..range.end - offset];
+            match extent {
+                Extent::Hole => dst.fill(0),
+                Extent::Cached(block_id) => {
+                    get_block_cache(block_id as usize, Arc::clone(block_device))
+                        .lock()
+                        .read(0, |data_block: &DataBlock| {
+                            let start = range.start % BLOCK_SZ;
+                            dst.copy_from_slice(&data_block[start..start + range.len()]);
+                        })
+                }
+                Extent::Direct(block_id) => block_device.read_blocks(block_id as usize, dst),
+            }
+        }
+        Ok(end - offset)
+    }
+
+    /// Same as `try_write_at`, but whole data blocks which are not resident are written
+    /// straight to the device from `buf`, consecutive ones with one `write_blocks`.
+    /// Partial blocks at both ends, resident blocks and blocks whose part of `buf`
+    /// doesn't meet `BlockDevice::alignment` are written through the cache.
+    /// Holes must be allocated first, the write stops short at the first one.
+    pub fn write_at_direct(
+        &mut self,
+        offset: usize,
+        buf: &[u8],
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<usize, EfsError> {
+        if self.is_inline() {
+            return self.try_write_at(offset, buf, data_area, block_device);
+        }
+        let end = offset
+            .saturating_add(buf.len())
+            .min(self.size as usize)
+            .min(MAX_FILE_SIZE);
+        if offset >= end {
+            return Ok(0);
+        }
+        let buf_addr = buf.as_ptr() as usize;
+        for (range, extent) in
+            self.direct_extents(offset, end, buf_addr, data_area, block_device)?
+        {
+            let src = &buf[range.start - offset..range.end - offset];
+            match extent {
+                // 空洞须先由调用者分配数据块，遇到时只写到它之前
+                Extent::Hole => return Ok(range.start - offset),
+                Extent::Cached(block_id) => {
+                    get_block_cache(block_id as usize, Arc::clone(block_device))
+                        .lock()
+                        .modify(0, |data_block: &mut DataBlock| {
+                            let start = range.start % BLOCK_SZ;
+                            data_block[start..start + range.len()].copy_from_slice(src);
+                        })
+                }
+                Extent::Direct(block_id) => block_device.write_blocks(block_id as usize, src),
+            }
+        }
+        Ok(end - offset)
+    }
+
+    // 把 [start, end) 按数据块切分，整块并且不在缓存中的块与后面物理上连续的同类块合并。
+    // buf_addr 是起始字节在缓冲区中的地址，不满足设备对齐要求的块也经过块缓存
+    fn direct_extents(
+        &self,
+        start: usize,
+        end: usize,
+        buf_addr: usize,
+        data_area: &Range<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Result<Vec<(Range<usize>, Extent)>, EfsError> {
+        let mut extents: Vec<(Range<usize>, Extent)> = Vec::new();
+        let mut pos = start;
+        while pos < end {
+            let end_current_block = ((pos / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+            let block_id =
+                self.try_get_block_id((pos / BLOCK_SZ) as u32, data_area, block_device)?;
+            let extent = if block_id == 0 {
+                Extent::Hole
+            } else if end_current_block - pos < BLOCK_SZ
+                || !(buf_addr + pos - start).is_multiple_of(block_device.alignment())
+                || BLOCK_CACHE_MANAGER
+                    .lock()
+                    .is_resident(block_id as usize, block_device)
+            {
+                Extent::Cached(block_id)
+            } else {
+                Extent::Direct(block_id)
+            };
+            match (extents.last_mut(), extent) {
+                (Some((range, Extent::Direct(first))), Extent::Direct(block_id))
+                    if range.end == pos
+                        && *first as usize + range.len() / BLOCK_SZ == block_id as usize =>
+                {
+                    range.end = end_current_block;
+                }
+                (_, extent) => extents.push((pos..end_current_block, extent)),
+            }
+            pos = end_current_block;
+        }
+        Ok(extents)
     }
 }
 
@@ -387,6 +1480,8 @@ pub struct DirEntry {
     inode_number: u32,
 }
 pub const DIRENT_SZ: usize = 32;
+/// Max number of entries a directory can hold within `MAX_FILE_SIZE`
+pub const MAX_DIR_ENTRIES: u32 = (MAX_FILE_SIZE / DIRENT_SZ) as u32;
 impl DirEntry {
     pub fn empty() -> Self {
         Self {
@@ -394,6 +1489,13 @@ impl DirEntry {
             inode_number: 0,
         }
     }
+    /// Whether `name` can be stored in an entry: not empty, `.` or `..`, at most
+    /// 27 bytes and without `/` or NUL
+    pub fn is_valid_name(name: &str) -> bool {
+        !matches!(name, "" | "." | "..")
+            && name.len() <= NAME_LENGTH_LIMIT
+            && !name.bytes().any(|byte| byte == b'/' || byte == 0)
+    }
     pub fn new(name: &str, inode_number: u32) -> Self {
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
         bytes[..name.len()].copy_from_slice(name.as_bytes());
@@ -408,11 +1510,32 @@ impl DirEntry {
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         unsafe { core::slice::from_raw_parts_mut(self as *const _ as usize as *mut u8, DIRENT_SZ) }
     }
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// Bytes of the name up to the NUL terminator, as stored on disk
+    pub fn name_bytes(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+    /// The name, with bytes which are not valid UTF-8, e.g. after corruption or
+    /// from foreign tools, replaced by U+FFFD
+    pub fn name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.name_bytes())
     }
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
+    /// Point the entry at another inode, keeping the name bytes as they are
+    pub fn set_inode_number(&mut self, inode_number: u32) {
+        self.inode_number = inode_number;
+    }
+    /// Whether the name is NUL-terminated UTF-8, so that `name` doesn't panic
+    pub fn is_valid(&self) -> bool {
+        self.name
+            .iter()
+            .position(|byte| *byte == 0)
+            .is_some_and(|len| core::str::from_utf8(&self.name[..len]).is_ok())
+    }
 }
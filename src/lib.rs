@@ -1,15 +1,36 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate alloc;
 mod bitmap;
 mod block_cache;
 mod block_dev;
 mod efs;
+mod error;
+#[cfg(feature = "std")]
+mod flusher;
 mod layout;
+mod mount;
+mod stream;
+mod trace;
 mod vfs;
 /// Use a block size of 512 bytes
 pub const BLOCK_SZ: usize = 512;
-pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use block_cache::{
+    block_cache_try_sync_all, checkpoint, emergency_flush, flush_dirty_sorted,
+    invalidate_device_cache, sync_device, BlockCache, BlockCacheManager, BufferPool,
+    EvictionPolicy, BLOCK_CACHE_MANAGER,
+};
+pub use block_dev::{BlockDevice, RetryingBlockDevice, StaticBlockDevice};
+pub use efs::{
+    DataAllocator, EasyFileSystem, InodeAllocPolicy, Issue, LayoutInfo,
+    OpenFlags, SizeRepair, UsageReport,
+};
+pub use error::EfsError;
+#[cfg(feature = "std")]
+pub use flusher::{start_flusher, stop_flusher};
+pub use layout::{DirEntry, DiskInodeType, SuperBlock, LABEL_LEN};
+pub use mount::MountTable;
+pub use stream::{InodeBytes, InodeWriter};
+pub use trace::{set_trace_hook, TraceEvent};
+pub use vfs::{Inode, IoCounters, ReleasePlan};
 use block_cache::block_cache_sync_all;
\ No newline at end of file
@@ -0,0 +1,74 @@
+// 挂载表：把绝对路径按最长的挂载点前缀分派到对应的文件系统
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+
+use crate::{efs::EasyFileSystem, vfs::Inode};
+
+/// Maps mount points to filesystems and resolves absolute paths to the
+/// filesystem mounted at the longest matching mount point
+#[derive(Default)]
+pub struct MountTable {
+    // 挂载点去掉了末尾的 `/`，根目录记为空串
+    mounts: Vec<(String, Arc<Mutex<EasyFileSystem>>)>,
+}
+
+impl MountTable {
+    pub fn new() -> Self {
+        Self { mounts: Vec::new() }
+    }
+
+    fn normalize(path: &str) -> Option<&str> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        Some(path.trim_end_matches('/'))
+    }
+
+    /// Mount `efs` at absolute path `mount_point`, fail if something is
+    /// already mounted there
+    pub fn mount(&mut self, mount_point: &str, efs: Arc<Mutex<EasyFileSystem>>) -> bool {
+        let Some(mount_point) = Self::normalize(mount_point) else {
+            return false;
+        };
+        if self.mounts.iter().any(|(point, _)| point == mount_point) {
+            return false;
+        }
+        self.mounts.push((mount_point.to_string(), efs));
+        true
+    }
+
+    /// Remove the filesystem mounted at `mount_point` and return it
+    pub fn unmount(&mut self, mount_point: &str) -> Option<Arc<Mutex<EasyFileSystem>>> {
+        let mount_point = Self::normalize(mount_point)?;
+        let idx = self
+            .mounts
+            .iter()
+            .position(|(point, _)| point == mount_point)?;
+        Some(self.mounts.remove(idx).1)
+    }
+
+    /// Find the filesystem an absolute `path` belongs to. Returns it together
+    /// with its root inode and the rest of `path` relative to the mount point,
+    /// which can be passed to `Inode::find_path` of the root inode.
+    pub fn resolve(&self, path: &str) -> Option<(Arc<Mutex<EasyFileSystem>>, Arc<Inode>, String)> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        // 只在路径分量的边界上匹配，`/mnt` 不能匹配 `/mnt2`
+        let (point, efs) = self
+            .mounts
+            .iter()
+            .filter(|(point, _)| {
+                path.strip_prefix(point.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(point, _)| point.len())?;
+        let rest = path[point.len()..].trim_start_matches('/');
+        let root = Arc::new(EasyFileSystem::root_inode(efs));
+        Some((Arc::clone(efs), root, rest.to_string()))
+    }
+}
@@ -0,0 +1,101 @@
+// 在 Inode 之上提供按字节流读写的适配器，便于配合 write! 和迭代器使用
+use core::fmt;
+
+use crate::{vfs::Inode, BLOCK_SZ};
+
+/// Iterator over the bytes of an inode from a given offset to the end of file.
+/// Data is read one block-sized chunk at a time.
+pub struct InodeBytes<'a> {
+    inode: &'a Inode,
+    offset: usize,
+    buf: [u8; BLOCK_SZ],
+    // buf 中尚未返回的字节范围
+    pos: usize,
+    len: usize,
+}
+
+impl<'a> InodeBytes<'a> {
+    pub fn new(inode: &'a Inode, offset: usize) -> Self {
+        Self {
+            inode,
+            offset,
+            buf: [0u8; BLOCK_SZ],
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Iterator for InodeBytes<'_> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        if self.pos == self.len {
+            self.len = self.inode.read_at(self.offset, &mut self.buf);
+            self.offset += self.len;
+            self.pos = 0;
+            if self.len == 0 {
+                return None;
+            }
+        }
+        self.pos += 1;
+        Some(self.buf[self.pos - 1])
+    }
+}
+
+/// Sequential writer into an inode starting from a given offset,
+/// usable as the target of `write!`
+pub struct InodeWriter<'a> {
+    inode: &'a Inode,
+    offset: usize,
+}
+
+impl<'a> InodeWriter<'a> {
+    pub fn new(inode: &'a Inode, offset: usize) -> Self {
+        Self { inode, offset }
+    }
+
+    /// Current write offset
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Write all bytes of `bytes` in block-sized chunks, return the number of
+    /// bytes written, which is less than the input if the inode is full
+    pub fn write_bytes(&mut self, bytes: impl IntoIterator<Item = u8>) -> usize {
+        let mut bytes = bytes.into_iter();
+        let mut buf = [0u8; BLOCK_SZ];
+        let mut total = 0usize;
+        loop {
+            let mut len = 0;
+            for (slot, byte) in buf.iter_mut().zip(&mut bytes) {
+                *slot = byte;
+                len += 1;
+            }
+            if len == 0 {
+                break;
+            }
+            let written = self.write_chunk(&buf[..len]);
+            total += written;
+            if written < len {
+                break;
+            }
+        }
+        total
+    }
+
+    fn write_chunk(&mut self, buf: &[u8]) -> usize {
+        let written = self.inode.write_at(self.offset, buf);
+        self.offset += written;
+        written
+    }
+}
+
+impl fmt::Write for InodeWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write_chunk(s.as_bytes()) == s.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
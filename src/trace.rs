@@ -0,0 +1,50 @@
+// 调试用的跟踪钩子：安装后文件系统和块缓存在各种操作发生时回调它
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// High-level operations reported to the trace hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// An inode was created and linked into a directory
+    Create { inode: u32 },
+    /// A directory entry was removed
+    Unlink { inode: u32 },
+    /// Bytes read from an inode
+    Read {
+        inode: u32,
+        offset: usize,
+        len: usize,
+    },
+    /// Bytes written to an inode
+    Write {
+        inode: u32,
+        offset: usize,
+        len: usize,
+    },
+    /// A data block was allocated
+    Alloc { block: u32 },
+    /// A data block was released
+    Free { block: u32 },
+    /// A block cache was evicted
+    Evict { block: usize },
+}
+
+// 为空表示没有安装钩子
+static TRACE_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Install a hook called on every traced operation, or remove it with None.
+/// The hook runs with filesystem and cache locks held, so it must not call back into easy-fs.
+pub fn set_trace_hook(hook: Option<fn(&TraceEvent)>) {
+    let ptr = hook.map_or(core::ptr::null_mut(), |hook| hook as *mut ());
+    TRACE_HOOK.store(ptr, Ordering::Release);
+}
+
+/// Report an event to the hook, `event` is only built when a hook is installed
+pub(crate) fn trace(event: impl FnOnce() -> TraceEvent) {
+    let ptr = TRACE_HOOK.load(Ordering::Acquire);
+    if ptr.is_null() {
+        return;
+    }
+    // 只有 set_trace_hook 会写入非空指针，它总是来自 fn(&TraceEvent)
+    let hook = unsafe { core::mem::transmute::<*mut (), fn(&TraceEvent)>(ptr) };
+    hook(&event());
+}
@@ -1,12 +1,31 @@
 // 服务于文件的系统调用
-use super::block_cache_sync_all;
+use super::{block_cache_sync_all, block_cache_try_sync_all};
 use crate::{
-    block_cache::get_block_cache,
+    block_cache::{
+        checkpoint, device_key, get_block_cache, get_block_cache_zeroed, prefetch_blocks,
+        sync_blocks,
+    },
     block_dev::BlockDevice,
     efs::EasyFileSystem,
-    layout::{DirEntry, DiskInode, DiskInodeType, DIRENT_SZ},
+    error::{check_corrupt, ensure, EfsError},
+    layout::{
+        check_block_tag, DirEntry, DiskInode, DiskInodeType, BLOCK_TAG_INODE, BLOCK_TAG_OFFSET,
+        DIRENT_SZ, DISK_INODE_SZ, INODES_PER_BLOCK, MAX_FILE_SIZE,
+    },
+    trace::{trace, TraceEvent},
+    BLOCK_SZ,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicUsize, Ordering},
 };
-use alloc::{string::String, sync::Arc, vec::Vec};
 use spin::{Mutex, MutexGuard};
 
 /*
@@ -20,6 +39,67 @@ pub struct Inode {
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    // 查找到该 Inode 的父目录的位置，父目录的配额对该 Inode 有效
+    parent: Option<(usize, usize)>,
+    // 通过该 Inode 读写的字节数，只保存在内存中
+    bytes_read: AtomicUsize,
+    bytes_written: AtomicUsize,
+    // 上一次读取结束的位置，下一次读取从这里开始时视为顺序读
+    last_read_end: AtomicUsize,
+    // 创建句柄时 DiskInode 的代数，不一致说明该 inode 已被释放并重新分配
+    generation: u32,
+}
+
+// 打开的 Inode 句柄，以 (设备, DiskInode 所在块, 块内偏移, 代数) 区分
+type OpenKey = (usize, usize, usize, u32);
+
+struct OpenInode {
+    handles: usize,
+    // 目录项已被 unlink 删除，最后一个句柄关闭时释放 inode
+    unlinked: bool,
+}
+
+// 按 DiskInode 的位置分片，打开和关闭不同 inode 的句柄很少争用同一把锁
+const OPEN_INODE_SHARDS: usize = 16;
+
+static OPEN_INODES: [Mutex<BTreeMap<OpenKey, OpenInode>>; OPEN_INODE_SHARDS] =
+    [const { Mutex::new(BTreeMap::new()) }; OPEN_INODE_SHARDS];
+
+fn open_inodes(key: &OpenKey) -> &'static Mutex<BTreeMap<OpenKey, OpenInode>> {
+    &OPEN_INODES[(key.1 * INODES_PER_BLOCK + key.2 / DISK_INODE_SZ) % OPEN_INODE_SHARDS]
+}
+
+// 最后一个句柄关闭时已被删除的 inode，以 (设备, DiskInode 所在块, 块内偏移, 代数) 区分。
+// Drop 中不能锁文件系统，留给下一个分配 inode 或删除目录项的操作，或者 reap 释放
+static ORPHANS: Mutex<Vec<OpenKey>> = Mutex::new(Vec::new());
+
+/// Take the positions and generations of the orphaned disk inodes on `block_device`
+pub(crate) fn take_orphans(block_device: &Arc<dyn BlockDevice>) -> Vec<(usize, usize, u32)> {
+    let device = device_key(block_device);
+    let mut orphans = ORPHANS.lock();
+    let mut taken = Vec::new();
+    orphans.retain(|&(key, block_id, block_offset, generation)| {
+        if key != device {
+            return true;
+        }
+        taken.push((block_id, block_offset, generation));
+        false
+    });
+    taken
+}
+
+/// Bytes read and written through an `Inode` since it was constructed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoCounters {
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+}
+
+/// Inodes and blocks released by a destructive operation, or which would be in dry-run mode
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReleasePlan {
+    pub inodes: Vec<u32>,
+    pub blocks: Vec<u32>,
 }
 
 impl Inode {
@@ -42,146 +122,1764 @@ impl Inode {
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
-        Self {
+        let mut inode = Self {
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            parent: None,
+            bytes_read: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+            last_read_end: AtomicUsize::new(0),
+            generation: 0,
+        };
+        inode.generation = inode.read_disk_inode(|disk_inode| disk_inode.generation);
+        if cfg!(feature = "block-tags") {
+            let tag = get_block_cache(inode.block_id, Arc::clone(&inode.block_device))
+                .lock()
+                .read(BLOCK_TAG_OFFSET, |tag: &u32| *tag);
+            check_block_tag(tag, BLOCK_TAG_INODE);
+        }
+        let key = inode.open_key();
+        open_inodes(&key)
+            .lock()
+            .entry(key)
+            .or_insert(OpenInode {
+                handles: 0,
+                unlinked: false,
+            })
+            .handles += 1;
+        inode
+    }
+
+    fn open_key(&self) -> OpenKey {
+        (
+            device_key(&self.block_device),
+            self.block_id,
+            self.block_offset,
+            self.generation,
+        )
+    }
+
+    /// Whether the inode of this handle has been released and reallocated
+    pub fn is_stale(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| self.validate(disk_inode).is_err())
+    }
+
+    fn validate(&self, disk_inode: &DiskInode) -> Result<(), EfsError> {
+        if disk_inode.generation == self.generation {
+            Ok(())
+        } else {
+            Err(EfsError::Stale)
+        }
+    }
+
+    /// Get the I/O counters of current inode
+    pub fn io_counters(&self) -> IoCounters {
+        IoCounters {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
         }
     }
 
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
+        check_corrupt(self.try_find(name)).ok()
+    }
+
+    /// Same as `find`, but fail with `EfsError::NotDir` if current inode is a regular file
+    pub fn try_find(&self, name: &str) -> Result<Arc<Inode>, EfsError> {
         let fs = self.fs.lock();
+        let inode_id = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            if !disk_inode.is_dir() {
+                return Err(EfsError::NotDir);
+            }
+            self.find_inode_id(name, disk_inode, &fs)
+                .ok_or(EfsError::NotFound)
+        })?;
+        // a corrupt entry may point outside of the inode area
+        if inode_id as usize >= fs.inode_bitmap.maximum() {
+            return Err(EfsError::NotFound);
+        }
+        // 子 inode 可能和当前 inode 在同一个块中，须在释放块缓存的锁之后再创建
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Ok(self.child(block_id, block_offset))
+    }
+
+    /// Look up a `/`-separated path relative to current directory. Empty and
+    /// `.` components are skipped, and `..` never goes above current
+    /// directory, so current directory can serve as a confined root.
+    pub fn find_path(&self, path: &str) -> Option<Arc<Inode>> {
+        // 沿路径进入的各级 Inode，为空表示停留在当前目录
+        let mut stack: Vec<Arc<Inode>> = Vec::new();
+        for name in path.split('/') {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    stack.pop();
+                }
+                _ => {
+                    let dir = stack.last().map_or(self, |inode| inode.as_ref());
+                    if !dir.is_dir() {
+                        return None;
+                    }
+                    let inode = dir.find(name)?;
+                    stack.push(inode);
+                }
+            }
+        }
+        Some(stack.pop().unwrap_or_else(|| {
+            let mut inode = Self::new(
+                self.block_id as u32,
+                self.block_offset,
+                self.fs.clone(),
+                self.block_device.clone(),
+            );
+            inode.parent = self.parent;
+            Arc::new(inode)
+        }))
+    }
+
+    /// Number of directory entries referring to current inode, including
+    /// `.` and the `..` of subdirectories for a directory
+    pub fn nlink(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    /// Size of current inode in bytes, taken from the disk inode without reading any data
+    pub fn len(&self) -> u64 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size as u64)
+    }
+
+    /// Whether current inode holds no data at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether current inode is a directory with nothing but `.` and `..`
+    pub fn is_empty_dir(&self) -> bool {
+        self.is_dir() && self.len() == 2 * DIRENT_SZ as u64
+    }
+
+    /// Number of entries of current directory as listed by `ls`, taken from the
+    /// size without reading any entry. A trailing partial entry isn't counted,
+    /// and a regular file has no entries.
+    pub fn entry_count(&self) -> usize {
+        let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
+            if disk_inode.is_dir() {
+                disk_inode.size as usize / DIRENT_SZ
+            } else {
+                0
+            }
         })
     }
 
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
-        assert!(disk_inode.is_dir());
+    /// Create a vfs inode for an entry of current directory
+    fn child(&self, block_id: u32, block_offset: usize) -> Arc<Inode> {
+        let mut inode = Self::new(
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        );
+        inode.parent = Some((self.block_id, self.block_offset));
+        Arc::new(inode)
+    }
+
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        fs: &EasyFileSystem,
+    ) -> Option<u32> {
+        self.find_dirent(name, disk_inode, fs)
+            .map(|(_, inode_id)| inode_id)
+    }
+
+    /// Find the index and the inode number of a directory entry,
+    /// a regular file has no entries
+    fn find_dirent(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        fs: &EasyFileSystem,
+    ) -> Option<(usize, u32)> {
+        if !disk_inode.is_dir() {
+            return None;
+        }
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
         let mut dirent = DirEntry::empty();
         for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+            // size 之内的读取总是读满一个目录项
+            disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device);
+            if fs.names_match(dirent.name_bytes(), name) {
+                return Some((i, dirent.inode_number()));
             }
         }
         None
     }
 
+    /// Names of all entries of current directory, `.` and `..` included
     pub fn ls(&self) -> Vec<String> {
-        let _fs = &self.fs;
+        check_corrupt(self.try_ls()).unwrap_or_default()
+    }
+
+    /// Same as `ls`, but fail with `EfsError::NotDir` if current inode is a regular file
+    pub fn try_ls(&self) -> Result<Vec<String>, EfsError> {
+        let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
+            self.validate(disk_inode)?;
+            if !disk_inode.is_dir() {
+                return Err(EfsError::NotDir);
+            }
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             for i in 0..file_count {
                 let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device),
-                    DIRENT_SZ
-                );
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
                 v.push(String::from(dirent.name()));
             }
-            v
+            Ok(v)
         })
     }
 
+    /// List at most `count` entries starting from the `start`-th one,
+    /// with their inode numbers
+    pub fn ls_range(&self, start: usize, count: usize) -> Vec<(String, u32)> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if self.validate(disk_inode).is_err() || !disk_inode.is_dir() {
+                return Vec::new();
+            }
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let end = start.saturating_add(count).min(file_count);
+            let mut dirent = DirEntry::empty();
+            (start.min(end)..end)
+                .map(|i| {
+                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                    (String::from(dirent.name()), dirent.inode_number())
+                })
+                .collect()
+        })
+    }
+
+    /// Read the entries starting from the `start_idx`-th one into `out` without
+    /// allocating, e.g. for `getdents`. `encode` serializes an entry into the rest
+    /// of the buffer and returns the bytes it used, or 0 if the entry doesn't fit,
+    /// which stops reading. Return the number of entries and bytes written,
+    /// the next call can continue from `start_idx` plus the entries written.
+    pub fn read_dir_into(
+        &self,
+        start_idx: usize,
+        out: &mut [u8],
+        encode: impl Fn(&DirEntry, &mut [u8]) -> usize,
+    ) -> (usize, usize) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if self.validate(disk_inode).is_err() || !disk_inode.is_dir() {
+                return (0, 0);
+            }
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let mut dirent = DirEntry::empty();
+            let (mut entries, mut bytes) = (0, 0);
+            for i in start_idx..file_count {
+                if bytes == out.len() {
+                    break;
+                }
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                let len = encode(&dirent, &mut out[bytes..]);
+                if len == 0 {
+                    break;
+                }
+                assert!(len <= out.len() - bytes);
+                entries += 1;
+                bytes += len;
+            }
+            (entries, bytes)
+        })
+    }
+
+    /// Check that the size of current directory is a whole number of entries.
+    /// A trailing partial entry, e.g. left by a crash in the middle of an
+    /// append, is ignored when listing and looking up, and gets overwritten
+    /// by the next append. Entries whose name can't be decoded are reported too.
+    pub fn check_dir(&self) -> Result<(), EfsError> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            ensure!(disk_inode.is_dir(), EfsError::NotDir);
+            if !(disk_inode.size as usize).is_multiple_of(DIRENT_SZ) {
+                return Err(EfsError::CorruptDir);
+            }
+            let mut dirent = DirEntry::empty();
+            for i in 0..disk_inode.size as usize / DIRENT_SZ {
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                ensure!(dirent.is_valid(), EfsError::CorruptDir);
+            }
+            Ok(())
+        })
+    }
+
+    /// Create a regular file under current directory
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+
+    /// Create a directory under current directory
+    pub fn mkdir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+
+    /// Create an inode of the given type and link it under current directory
+    pub fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
+        check_corrupt(self.try_create_inode(name, type_)).ok()
+    }
+
+    /// Same as `create_inode`, but report why the inode can't be created.
+    /// Nothing is changed on failure, e.g. `EfsError::NoFreeInode` when all inodes are in use.
+    pub fn try_create_inode(
+        &self,
+        name: &str,
+        type_: DiskInodeType,
+    ) -> Result<Arc<Inode>, EfsError> {
         let mut fs = self.fs.lock();
         if self
-            .modify_disk_inode(|root_inode| {
-                assert!(root_inode.is_dir());
-                self.find_inode_id(name, root_inode)
-            })
+            .read_disk_inode(|root_inode| {
+                self.validate(root_inode)?;
+                // 在普通文件中追加目录项会破坏其内容
+                if !root_inode.is_dir() {
+                    return Err(EfsError::NotDir);
+                }
+                Ok(self.find_inode_id(name, root_inode, &fs))
+            })?
             .is_some()
         {
+            return Err(EfsError::AlreadyExists);
+        }
+
+        self.link_new_inode(name, type_, &mut fs)
+    }
+
+    /// Create a regular file without checking whether `name` already exists.
+    /// This skips scanning the whole directory, for bulk import of names
+    /// known to be unique; a duplicate name leaves two entries that can't be
+    /// told apart, so it must never be used on untrusted input. Invalid names,
+    /// see `DirEntry::is_valid_name`, are still refused.
+    pub fn create_unchecked(&self, name: &str) -> Option<Arc<Inode>> {
+        let mut fs = self.fs.lock();
+        if !self.read_disk_inode(|root_inode| root_inode.is_dir()) {
             return None;
         }
+        self.link_new_inode(name, DiskInodeType::File, &mut fs).ok()
+    }
 
-        let new_inode_id = fs.alloc_inode();
+    /// Create regular files for all of `names`, growing the directory once and
+    /// writing back once at the end, for bulk import. An entry is None if its name
+    /// is invalid, already exists or appears earlier in the batch, or if it can't be allocated.
+    pub fn create_batch(&self, names: &[&str]) -> Vec<Option<Arc<Inode>>> {
+        let mut fs = self.fs.lock();
+        let mut result: Vec<Option<u32>> = names.iter().map(|_| None).collect();
+        if self.read_disk_inode(|dir_inode| {
+            self.validate(dir_inode)
+                .map(|_| dir_inode.is_dir() && dir_inode.nlink > 0)
+        }) != Ok(true)
+        {
+            return result.into_iter().map(|_| None).collect();
+        }
+        let case_insensitive = fs.is_case_insensitive();
+        let fold = |name: &str| {
+            if case_insensitive {
+                name.to_ascii_lowercase()
+            } else {
+                String::from(name)
+            }
+        };
+        let (file_count, mut existing) = self.read_disk_inode(|dir_inode| {
+            let file_count = dir_inode.size as usize / DIRENT_SZ;
+            let mut dirent = DirEntry::empty();
+            let existing: BTreeSet<String> = (0..file_count)
+                .map(|i| {
+                    dir_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                    fold(&dirent.name())
+                })
+                .collect();
+            (file_count, existing)
+        });
+        // 先挑出不重复的名字，受目录项数上限的限制
+        let room = (fs.max_dir_entries() as usize).saturating_sub(file_count);
+        let picked: Vec<usize> = (0..names.len())
+            .filter(|&i| DirEntry::is_valid_name(names[i]) && existing.insert(fold(names[i])))
+            .take(room)
+            .collect();
+        let new_size = ((file_count + picked.len()) * DIRENT_SZ) as u32;
+        let blocks_needed = self.read_disk_inode(|dir_inode| {
+            if new_size > dir_inode.size {
+                dir_inode.blocks_num_needed(new_size)
+            } else {
+                0
+            }
+        });
+        if self.check_quota(blocks_needed, &fs).is_err() {
+            return result.into_iter().map(|_| None).collect();
+        }
+        let mut dirents: Vec<u8> = Vec::new();
+        for i in picked {
+            let new_inode_id = match fs.alloc_inode() {
+                Some(inode_id) => inode_id,
+                None => break,
+            };
+            let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(block_offset, |new_inode: &mut DiskInode| {
+                    new_inode.initialize(DiskInodeType::File);
+                    if fs.is_inline_data() {
+                        new_inode.set_inline();
+                    }
+                });
+            dirents.extend_from_slice(DirEntry::new(names[i], new_inode_id).as_bytes());
+            result[i] = Some(new_inode_id);
+        }
+        // 一次性扩展目录并写入所有新目录项
+        let new_size = (file_count * DIRENT_SZ + dirents.len()) as u32;
+        let blocks_needed = self.read_disk_inode(|dir_inode| {
+            if new_size > dir_inode.size {
+                dir_inode.blocks_num_needed(new_size)
+            } else {
+                0
+            }
+        });
+        let Some(new_blocks) = fs.alloc_data_blocks(blocks_needed as usize) else {
+            // 新 inode 还没有链接到目录中，也还没有数据块
+            for inode_id in result.iter().flatten() {
+                assert!(fs.dealloc_inode(*inode_id));
+            }
+            return result.into_iter().map(|_| None).collect();
+        };
+        let new_inode_ids: Vec<u32> = result.iter().flatten().copied().collect();
+        let data_blocks = new_blocks.clone();
+        self.append_entries(
+            file_count * DIRENT_SZ,
+            &dirents,
+            new_blocks,
+            &new_inode_ids,
+            &data_blocks,
+            &fs,
+        );
+        for &inode in result.iter().flatten() {
+            trace(|| TraceEvent::Create { inode });
+        }
+        block_cache_sync_all();
+        self.sync_metadata(&fs);
+        for &inode_id in new_inode_ids.iter() {
+            Self::sync_inode_metadata(inode_id, &fs);
+        }
+        result
+            .into_iter()
+            .map(|inode_id| {
+                inode_id.map(|inode_id| {
+                    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+                    self.child(block_id, block_offset)
+                })
+            })
+            .collect()
+    }
+
+    /// Append `dirents` to current directory at `start`, mapping `new_blocks` for it.
+    /// The blocks holding the entries, the index blocks, the inodes `inode_ids` and the
+    /// allocation of them and of `data_blocks` are written back and flushed before the
+    /// inode block recording the new size is modified at all, so neither a flusher
+    /// nor an eviction can write the size ahead of the entries.
+    fn append_entries(
+        &self,
+        start: usize,
+        dirents: &[u8],
+        new_blocks: Vec<u32>,
+        inode_ids: &[u32],
+        data_blocks: &[u32],
+        fs: &EasyFileSystem,
+    ) {
+        let end = start + dirents.len();
+        // 在副本上扩展目录并写入目录项，屏障之后才写入 inode 块，
+        // 中途崩溃时 size 不会覆盖到没写完的目录项
+        let mut dir_inode = self.read_disk_inode(|dir_inode| dir_inode.clone());
+        self.increase_size(end as u32, &mut dir_inode, new_blocks);
+        dir_inode.write_at(start, dirents, &self.block_device);
+        let mut block_ids: Vec<usize> = (start / BLOCK_SZ..end.div_ceil(BLOCK_SZ))
+            .map(|inner_id| dir_inode.get_block_id(inner_id as u32, &self.block_device) as usize)
+            .collect();
+        block_ids.extend(
+            dir_inode
+                .index_block_ids(&self.block_device)
+                .into_iter()
+                .map(|block_id| block_id as usize),
+        );
+        block_ids.extend(
+            inode_ids
+                .iter()
+                .map(|&inode_id| fs.get_disk_inode_pos(inode_id).0 as usize),
+        );
+        block_ids.extend(fs.allocation_blocks(inode_ids, data_blocks));
+        // 与当前目录共用 inode 块的新 inode 只能和 size 一起写回
+        block_ids.retain(|&block_id| block_id != self.block_id);
+        checkpoint(&self.block_device, &block_ids);
+        self.modify_disk_inode(|disk_inode| *disk_inode = dir_inode);
+        sync_blocks(&self.block_device, &[self.block_id]);
+    }
+
+    /// Allocate and initialize a new inode, then append its entry to current directory
+    fn link_new_inode(
+        &self,
+        name: &str,
+        type_: DiskInodeType,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<Arc<Inode>, EfsError> {
+        if !DirEntry::is_valid_name(name) {
+            return Err(EfsError::InvalidName);
+        }
+        // 向下取整，覆盖掉末尾可能残留的不完整目录项，保证 size 总是 DIRENT_SZ 的整数倍
+        let (file_count, dir_blocks, nlink) = self.read_disk_inode(|root_inode| {
+            let file_count = root_inode.size / DIRENT_SZ as u32;
+            let new_size = (file_count + 1) * DIRENT_SZ as u32;
+            (
+                file_count,
+                root_inode.blocks_num_needed(new_size),
+                root_inode.nlink,
+            )
+        });
+        // 已被删除、只是还有句柄打开的目录不能再添加目录项，否则释放时会丢失它们
+        if nlink == 0 {
+            return Err(EfsError::NotFound);
+        }
+        if file_count >= fs.max_dir_entries() {
+            return Err(EfsError::DirFull);
+        }
+        // 新目录保存 . 和 .. 的数据块
+        let dot_blocks = if type_ == DiskInodeType::Directory {
+            DiskInode::total_blocks(2 * DIRENT_SZ as u32)
+        } else {
+            0
+        };
+        self.check_quota(dir_blocks + dot_blocks, fs)?;
+        if fs.free_data_blocks() < (dir_blocks + dot_blocks) as usize {
+            return Err(EfsError::NoSpace);
+        }
+        // 以上只做检查；inode 或数据块分配失败时目录和数据块都还没有改动
+        let new_inode_id = fs.alloc_inode().ok_or(EfsError::NoFreeInode)?;
+        let Some(mut new_blocks) = fs.alloc_data_blocks((dir_blocks + dot_blocks) as usize) else {
+            assert!(fs.dealloc_inode(new_inode_id));
+            return Err(EfsError::NoSpace);
+        };
+        let dot_blocks = new_blocks.split_off(dir_blocks as usize);
         let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(new_inode_block_id as usize , Arc::clone(&self.block_device))
+        let new_inode_blocks =
+            get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                    new_inode.initialize(type_);
+                    if type_ == DiskInodeType::File && fs.is_inline_data() {
+                        new_inode.set_inline();
+                    }
+                    if type_ == DiskInodeType::Directory {
+                        let parent_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+                        new_inode.init_dot_entries(
+                            new_inode_id,
+                            parent_id,
+                            dot_blocks,
+                            &self.block_device,
+                        );
+                    }
+                    new_inode.data_block_ids(&self.block_device)
+                });
+        if type_ == DiskInodeType::Directory {
+            // 子目录的 .. 指向当前目录。新目录完整写回之后才链接到当前目录，
+            // 任何时刻都不会出现缺少 . 或 .. 的目录
+            self.modify_disk_inode(|root_inode| root_inode.nlink += 1);
+            let mut block_ids: Vec<usize> = new_inode_blocks
+                .iter()
+                .map(|&block_id| block_id as usize)
+                .collect();
+            block_ids.push(new_inode_block_id as usize);
+            sync_blocks(&self.block_device, &block_ids);
+        }
+        let mut data_blocks = new_blocks.clone();
+        data_blocks.extend_from_slice(&new_inode_blocks);
+        self.append_entries(
+            file_count as usize * DIRENT_SZ,
+            DirEntry::new(name, new_inode_id).as_bytes(),
+            new_blocks,
+            &[new_inode_id],
+            &data_blocks,
+            fs,
+        );
+        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+        block_cache_try_sync_all()?;
+        self.sync_metadata(fs);
+        Self::sync_inode_metadata(new_inode_id, fs);
+        trace(|| TraceEvent::Create {
+            inode: new_inode_id,
+        });
+        Ok(self.child(block_id, block_offset))
+    }
+
+    /// Create a file, or truncate it to zero if it already exists as a regular file
+    pub fn create_or_truncate(&self, name: &str) -> Option<Arc<Inode>> {
+        // 查找与创建或截断在同一次加锁内完成，期间不会有别的句柄创建或删除同名项
+        let mut fs = self.fs.lock();
+        let inode_id = self.read_disk_inode(|dir_inode| {
+            if self.validate(dir_inode).is_err() || !dir_inode.is_dir() {
+                return None;
+            }
+            Some(self.find_inode_id(name, dir_inode, &fs))
+        })?;
+        let Some(inode_id) = inode_id else {
+            return self.link_new_inode(name, DiskInodeType::File, &mut fs).ok();
+        };
+        if inode_id as usize >= fs.inode_bitmap.maximum() {
+            return None;
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        let inode = self.child(block_id, block_offset);
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return None;
+        }
+        inode.clear_locked(&mut fs).ok()?;
+        Some(inode)
+    }
+
+    /// Rename an entry of this directory.
+    /// If `new_name` already exists, it is rebound to the source inode first
+    /// and the old target loses a link afterwards, so a crash never leaves
+    /// `new_name` pointing nowhere. Like with `unlink`, the old target is
+    /// released once it has no links and no open handles. Return false if
+    /// the written blocks fail write verification.
+    pub fn rename(&self, old_name: &str, new_name: &str) -> bool {
+        let mut fs = self.fs.lock();
+        let (old_dirent, new_dirent) = self.read_disk_inode(|dir_inode| {
+            (
+                self.find_dirent(old_name, dir_inode, &fs),
+                self.find_dirent(new_name, dir_inode, &fs),
+            )
+        });
+        if [old_name, new_name]
+            .iter()
+            .any(|name| matches!(*name, "." | ".."))
+            || !DirEntry::is_valid_name(new_name)
+        {
+            return false;
+        }
+        let (old_idx, inode_id) = match old_dirent {
+            Some(dirent) => dirent,
+            None => return false,
+        };
+        if old_name == new_name {
+            return true;
+        }
+        if Self::read_inode(inode_id, &fs, |disk_inode| disk_inode.is_immutable()) {
+            return false;
+        }
+        let dirent = DirEntry::new(new_name, inode_id);
+        match new_dirent {
+            // 不区分大小写时，只改变大小写的重命名找到的是同一个目录项
+            Some((new_idx, target_id)) if new_idx != old_idx => {
+                if Self::read_inode(target_id, &fs, |target| {
+                    target.is_dir() || target.is_immutable()
+                }) {
+                    return false;
+                }
+                let removed = self.modify_disk_inode(|dir_inode| {
+                    dir_inode.write_at(new_idx * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+                    self.remove_dirent(old_idx, dir_inode, &mut fs)
+                });
+                if removed.is_err() {
+                    return false;
+                }
+                trace(|| TraceEvent::Unlink { inode: target_id });
+                // 旧目标和 unlink 一样只减少一个链接，仍打开时等最后一个句柄关闭再释放
+                if Self::drop_link(target_id, &mut fs).is_err() {
+                    return false;
+                }
+            }
+            _ => {
+                self.modify_disk_inode(|dir_inode| {
+                    dir_inode.write_at(old_idx * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+                });
+            }
+        }
+        fs.bump_epoch();
+        let synced = block_cache_try_sync_all().is_ok();
+        self.sync_metadata(&fs);
+        Self::sync_inode_metadata(inode_id, &fs);
+        if let Some((_, target_id)) = new_dirent.filter(|&(_, target_id)| target_id != inode_id) {
+            Self::sync_inode_metadata(target_id, &fs);
+        }
+        synced
+    }
+
+    /// Swap the inodes bound to `name_a` in this directory and `name_b` in
+    /// `other_parent`, like `renameat2` with `RENAME_EXCHANGE`. Both names must exist.
+    /// Fails if a directory would be moved into its own subtree, or if the
+    /// written blocks fail write verification.
+    pub fn exchange(&self, name_a: &str, other_parent: &Inode, name_b: &str) -> bool {
+        if !Arc::ptr_eq(&self.fs, &other_parent.fs)
+            || [name_a, name_b]
+                .iter()
+                .any(|name| matches!(*name, "." | ".."))
+        {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        // 两个目录可能是同一个 inode，或位于同一个块中，不能同时锁住
+        let dirent_a = self.read_disk_inode(|dir_inode| {
+            dir_inode
+                .is_dir()
+                .then(|| self.find_dirent(name_a, dir_inode, &fs))
+        });
+        let dirent_b = other_parent.read_disk_inode(|dir_inode| {
+            dir_inode
+                .is_dir()
+                .then(|| other_parent.find_dirent(name_b, dir_inode, &fs))
+        });
+        let ((idx_a, id_a), (idx_b, id_b)) = match (dirent_a.flatten(), dirent_b.flatten()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return false,
+        };
+        // 同一目录中的同一名字，或两个名字是同一 inode 的硬链接
+        if id_a == id_b {
+            return true;
+        }
+        let dir_a = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        let dir_b = fs.get_inode_id(other_parent.block_id as u32, other_parent.block_offset);
+        if dir_a != dir_b {
+            let a_is_dir = Self::is_dir_inode(id_a, &fs);
+            let b_is_dir = Self::is_dir_inode(id_b, &fs);
+            if (a_is_dir && Self::is_ancestor(id_a, dir_b, &fs))
+                || (b_is_dir && Self::is_ancestor(id_b, dir_a, &fs))
+            {
+                return false;
+            }
+            // 移动到另一个目录下的子目录，其 .. 和两个父目录的链接数都要跟着改变
+            if a_is_dir {
+                Self::rebind_dirent(id_a, 1, dir_b, &fs);
+            }
+            if b_is_dir {
+                Self::rebind_dirent(id_b, 1, dir_a, &fs);
+            }
+            if a_is_dir != b_is_dir {
+                let (from, to) = if a_is_dir {
+                    (dir_a, dir_b)
+                } else {
+                    (dir_b, dir_a)
+                };
+                Self::lock_two_inodes(from, to, &fs, |from, to| {
+                    from.nlink -= 1;
+                    to.nlink += 1;
+                });
+            }
+        }
+        Self::rebind_dirent(dir_a, idx_a, id_b, &fs);
+        Self::rebind_dirent(dir_b, idx_b, id_a, &fs);
+        fs.bump_epoch();
+        let synced = block_cache_try_sync_all().is_ok();
+        for inode_id in [dir_a, dir_b, id_a, id_b] {
+            Self::sync_inode_metadata(inode_id, &fs);
+        }
+        synced
+    }
+
+    /// Copy the entry `name` of this directory, a regular file or a whole directory
+    /// tree, to `dst_name` in `dst_parent` with new inodes and data blocks.
+    /// If the copy fails partway, e.g. with `NoSpace`, everything it created is removed.
+    pub fn copy_tree(
+        &self,
+        name: &str,
+        dst_parent: &Inode,
+        dst_name: &str,
+    ) -> Result<Arc<Inode>, EfsError> {
+        let src = self.find(name).ok_or(EfsError::NotFound)?;
+        let type_ = if src.is_dir() {
+            let fs = self.fs.lock();
+            let src_id = fs.get_inode_id(src.block_id as u32, src.block_offset);
+            let dst_id = fs.get_inode_id(dst_parent.block_id as u32, dst_parent.block_offset);
+            if Self::is_ancestor(src_id, dst_id, &fs) {
+                return Err(EfsError::IntoSubtree);
+            }
+            DiskInodeType::Directory
+        } else {
+            DiskInodeType::File
+        };
+        let dst = dst_parent.try_create_inode(dst_name, type_)?;
+        match src.copy_contents(&dst) {
+            Ok(()) => Ok(dst),
+            Err(err) => {
+                drop(dst);
+                // 回滚失败时仍然报告复制的错误
+                let _ = dst_parent.remove_tree(dst_name, &mut ReleasePlan::default());
+                Err(err)
+            }
+        }
+    }
+
+    // 把当前 inode 的内容复制到新建的同类型的空 inode 中
+    fn copy_contents(&self, dst: &Inode) -> Result<(), EfsError> {
+        if self.is_dir() {
+            for (name, _) in self.ls_range(0, usize::MAX) {
+                if !matches!(name.as_str(), "." | "..") {
+                    self.copy_tree(&name, dst, &name)?;
+                }
+            }
+            return Ok(());
+        }
+        let mut buf = vec![0u8; 8 * BLOCK_SZ];
+        let mut offset = 0;
+        loop {
+            let len = self.read_at(offset, &mut buf);
+            if len == 0 {
+                return Ok(());
+            }
+            if dst.try_write_at(offset, &buf[..len])? < len {
+                return Err(EfsError::NoSpace);
+            }
+            offset += len;
+        }
+    }
+
+    /// Remove the entry `name` of this directory, a regular file, and return the
+    /// inodes and blocks released. If handles of the file are still open, it stays
+    /// readable and writable through them and its inode and blocks are only released
+    /// after the last one is dropped, by the next operation allocating an inode or
+    /// removing an entry, or by `EasyFileSystem::reap`.
+    /// In dry-run mode nothing is removed and only the plan is returned.
+    pub fn unlink(&self, name: &str) -> Result<ReleasePlan, EfsError> {
+        let mut fs = self.fs.lock();
+        fs.reap_orphans();
+        let (idx, inode_id) = self.read_disk_inode(|dir_inode| {
+            self.validate(dir_inode)?;
+            if !dir_inode.is_dir() {
+                return Err(EfsError::NotDir);
+            }
+            self.find_dirent(name, dir_inode, &fs)
+                .ok_or(EfsError::NotFound)
+        })?;
+        if matches!(name, "." | "..") || Self::is_dir_inode(inode_id, &fs) {
+            return Err(EfsError::IsDir);
+        }
+        let nlink = Self::read_inode(inode_id, &fs, |disk_inode| {
+            if disk_inode.is_immutable() {
+                return Err(EfsError::Immutable);
+            }
+            Ok(disk_inode.nlink)
+        })?;
+        let dir_blocks = self.read_disk_inode(|dir_inode| {
+            dir_inode.blocks_released_by(dir_inode.size - DIRENT_SZ as u32, &self.block_device)
+        });
+        let mut plan = ReleasePlan::default();
+        if fs.is_dry_run() {
+            if nlink <= 1 && !Self::is_open(inode_id, &fs) {
+                plan.inodes.push(inode_id);
+                plan.blocks = Self::inode_blocks(inode_id, &fs);
+            }
+            plan.blocks.extend(dir_blocks);
+            return Ok(plan);
+        }
+        self.modify_disk_inode(|dir_inode| self.remove_dirent(idx, dir_inode, &mut fs))?;
+        trace(|| TraceEvent::Unlink { inode: inode_id });
+        if let Some(file_blocks) = Self::drop_link(inode_id, &mut fs)? {
+            plan.inodes.push(inode_id);
+            plan.blocks = file_blocks;
+        }
+        plan.blocks.extend(dir_blocks);
+        fs.bump_epoch();
+        block_cache_try_sync_all()?;
+        self.sync_metadata(&fs);
+        Self::sync_inode_metadata(inode_id, &fs);
+        Ok(plan)
+    }
+
+    // 删除一个指向 inode_id 的目录项之后减少它的链接数，目录的链接随目录项一起消失。
+    // 没有链接时释放 inode 并返回释放的块；仍有打开的句柄时只做标记，
+    // 留到最后一个句柄关闭之后释放，返回 None
+    fn drop_link(inode_id: u32, fs: &mut EasyFileSystem) -> Result<Option<Vec<u32>>, EfsError> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        let (nlink, generation) = get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
             .lock()
-            .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.nlink = if disk_inode.is_dir() {
+                    0
+                } else {
+                    disk_inode.nlink.saturating_sub(1)
+                };
+                (disk_inode.nlink, disk_inode.generation)
             });
-        self.modify_disk_inode(|mut root_inode| {
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            self.increase_size(new_size as u32, &mut root_inode, &mut fs);
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
+        if nlink > 0 {
+            return Ok(None);
+        }
+        let key = (
+            device_key(&fs.block_device),
+            block_id as usize,
+            block_offset,
+            generation,
+        );
+        // 和 Drop 中的计数在同一把锁下检查，不会错过最后一个句柄的关闭
+        if let Some(open) = open_inodes(&key).lock().get_mut(&key) {
+            open.unlinked = true;
+            return Ok(None);
+        }
+        let blocks = Self::inode_blocks(inode_id, fs);
+        fs.release_inode(inode_id)?;
+        Ok(Some(blocks))
+    }
+
+    // inode_id 是否还有打开的句柄
+    fn is_open(inode_id: u32, fs: &EasyFileSystem) -> bool {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        let generation = Self::read_inode(inode_id, fs, |disk_inode| disk_inode.generation);
+        let key = (
+            device_key(&fs.block_device),
+            block_id as usize,
+            block_offset,
+            generation,
+        );
+        open_inodes(&key).lock().contains_key(&key)
+    }
+
+    // inode_id 的数据块和索引块
+    fn inode_blocks(inode_id: u32, fs: &EasyFileSystem) -> Vec<u32> {
+        Self::read_inode(inode_id, fs, |disk_inode| {
+            let mut blocks = disk_inode.data_block_ids(&fs.block_device);
+            blocks.extend(disk_inode.index_block_ids(&fs.block_device));
+            blocks
+        })
+    }
+
+    /// Remove the entry `name` of this directory and everything below it, return
+    /// the inodes and blocks released, or None if there is no such entry or
+    /// anything to remove is immutable. Like with `unlink`, inodes with open
+    /// handles are only released after the last one is dropped.
+    /// In dry-run mode nothing is removed and only the plan is returned.
+    pub fn remove_all(&self, name: &str) -> Option<ReleasePlan> {
+        check_corrupt(self.try_remove_all(name)).ok()
+    }
+
+    /// Like `remove_all`, but tell a missing entry from one that can't be
+    /// removed because it or something below it is immutable
+    pub fn try_remove_all(&self, name: &str) -> Result<ReleasePlan, EfsError> {
+        if matches!(name, "." | "..") {
+            return Err(EfsError::NotFound);
+        }
+        if !self.is_dir() {
+            return Err(EfsError::NotDir);
+        }
+        let mut inode_ids = Vec::new();
+        self.collect_tree(name, &mut inode_ids)
+            .ok_or(EfsError::NotFound)?;
+        let fs = self.fs.lock();
+        if inode_ids.iter().any(|&inode_id| {
+            Self::read_inode(inode_id, &fs, |disk_inode| disk_inode.is_immutable())
+        }) {
+            return Err(EfsError::Immutable);
+        }
+        // 删除目录项后当前目录缩小，可能释放最后一个数据块
+        let dir_blocks = self.read_disk_inode(|dir_inode| {
+            dir_inode.blocks_released_by(dir_inode.size - DIRENT_SZ as u32, &self.block_device)
+        });
+        let mut plan = ReleasePlan::default();
+        if fs.is_dry_run() {
+            for inode_id in inode_ids {
+                let nlink = Self::read_inode(inode_id, &fs, |disk_inode| {
+                    if disk_inode.is_dir() {
+                        1
+                    } else {
+                        disk_inode.nlink
+                    }
+                });
+                if nlink <= 1 && !Self::is_open(inode_id, &fs) {
+                    plan.inodes.push(inode_id);
+                    plan.blocks.extend(Self::inode_blocks(inode_id, &fs));
+                }
+            }
+            plan.blocks.extend(dir_blocks);
+            return Ok(plan);
+        }
+        drop(fs);
+        self.remove_tree(name, &mut plan)?;
+        plan.blocks.extend(dir_blocks);
+        Ok(plan)
+    }
+
+    // 按 remove_tree 的顺序收集目录项 name 及其下所有的 inode
+    fn collect_tree(&self, name: &str, inode_ids: &mut Vec<u32>) -> Option<()> {
+        let child = self.find(name)?;
+        if child.is_dir() {
+            for (child_name, _) in child.ls_range(0, usize::MAX) {
+                if !matches!(child_name.as_str(), "." | "..") {
+                    child.collect_tree(&child_name, inode_ids);
+                }
+            }
+        }
+        let fs = self.fs.lock();
+        inode_ids.push(fs.get_inode_id(child.block_id as u32, child.block_offset));
+        Some(())
+    }
+
+    // 删除当前目录中的目录项 name，是目录时先递归删除其中的所有内容，
+    // 把立即释放的 inode 和块记录到 plan 中
+    fn remove_tree(&self, name: &str, plan: &mut ReleasePlan) -> Result<(), EfsError> {
+        // 删除子项时目录缩小释放的块也记在该目录名下
+        let mut dir_blocks = Vec::new();
+        if let Some(child) = self.find(name) {
+            if child.is_dir() {
+                dir_blocks = child.read_disk_inode(|disk_inode| {
+                    let mut blocks = disk_inode.data_block_ids(&self.block_device);
+                    blocks.extend(disk_inode.index_block_ids(&self.block_device));
+                    blocks
+                });
+                for (child_name, _) in child.ls_range(0, usize::MAX) {
+                    if !matches!(child_name.as_str(), "." | "..") {
+                        child.remove_tree(&child_name, plan)?;
+                    }
+                }
+            }
+        }
+        let mut fs = self.fs.lock();
+        let found = self.read_disk_inode(|dir_inode| self.find_dirent(name, dir_inode, &fs));
+        if let Some((idx, inode_id)) = found {
+            let is_dir = Self::is_dir_inode(inode_id, &fs);
+            self.modify_disk_inode(|dir_inode| {
+                // 子目录的 .. 不再指向当前目录
+                if is_dir {
+                    dir_inode.nlink -= 1;
+                }
+                self.remove_dirent(idx, dir_inode, &mut fs)
+            })?;
+            trace(|| TraceEvent::Unlink { inode: inode_id });
+            // 上面查找时建立的句柄已经关闭，仍打开的只有调用者的句柄
+            match Self::drop_link(inode_id, &mut fs)? {
+                Some(blocks) => {
+                    plan.inodes.push(inode_id);
+                    plan.blocks.extend(if is_dir { dir_blocks } else { blocks });
+                }
+                None => {
+                    let kept = Self::inode_blocks(inode_id, &fs);
+                    plan.blocks
+                        .extend(dir_blocks.into_iter().filter(|block| !kept.contains(block)));
+                }
+            }
+            fs.bump_epoch();
+        }
+        block_cache_sync_all();
+        if let Some((_, inode_id)) = found {
+            self.sync_metadata(&fs);
+            Self::sync_inode_metadata(inode_id, &fs);
+        }
+        Ok(())
+    }
+
+    fn read_inode<V>(inode_id: u32, fs: &EasyFileSystem, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+
+    fn is_dir_inode(inode_id: u32, fs: &EasyFileSystem) -> bool {
+        Self::read_inode(inode_id, fs, |disk_inode| disk_inode.is_dir())
+    }
+
+    /// Whether directory `inode_id` is `ancestor` or lies below it, following `..` up to the root
+    fn is_ancestor(ancestor: u32, mut inode_id: u32, fs: &EasyFileSystem) -> bool {
+        // 目录树损坏时 .. 可能成环，最多向上走 inode 总数步
+        for _ in 0..fs.inode_bitmap.maximum() {
+            if inode_id == ancestor {
+                return true;
+            }
+            if inode_id == 0 {
+                return false;
+            }
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            let mut dirent = DirEntry::empty();
+            get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    disk_inode.read_at(DIRENT_SZ, dirent.as_bytes_mut(), &fs.block_device)
+                });
+            inode_id = dirent.inode_number();
+        }
+        true
+    }
+
+    /// Point the entry at `idx` of directory `dir_id` to `inode_id`, keeping its name
+    fn rebind_dirent(dir_id: u32, idx: usize, inode_id: u32, fs: &EasyFileSystem) {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(dir_id);
+        get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+            .lock()
+            .modify(block_offset, |dir_inode: &mut DiskInode| {
+                let mut dirent = DirEntry::empty();
+                dir_inode.read_at(idx * DIRENT_SZ, dirent.as_bytes_mut(), &fs.block_device);
+                dirent.set_inode_number(inode_id);
+                dir_inode.write_at(idx * DIRENT_SZ, dirent.as_bytes(), &fs.block_device);
+            });
+    }
+
+    /// Run `f` on the two different disk inodes `a` and `b` locked at the same time.
+    /// Their blocks are locked in the order of the inode numbers, and only once if
+    /// both lie in the same block, so that two operations locking the same pair
+    /// can't deadlock. Operations holding two disk inodes at once must go through here.
+    fn lock_two_inodes<V>(
+        a: u32,
+        b: u32,
+        fs: &EasyFileSystem,
+        f: impl FnOnce(&mut DiskInode, &mut DiskInode) -> V,
+    ) -> V {
+        assert_ne!(a, b);
+        let (block_a, offset_a) = fs.get_disk_inode_pos(a);
+        let (block_b, offset_b) = fs.get_disk_inode_pos(b);
+        let cache_a = get_block_cache(block_a as usize, Arc::clone(&fs.block_device));
+        if block_a == block_b {
+            return cache_a
+                .lock()
+                .modify(0, |disk_inodes: &mut [DiskInode; INODES_PER_BLOCK]| {
+                    let (idx_a, idx_b) = (offset_a / DISK_INODE_SZ, offset_b / DISK_INODE_SZ);
+                    let (low, high) = disk_inodes.split_at_mut(idx_a.max(idx_b));
+                    if idx_a < idx_b {
+                        f(&mut low[idx_a], &mut high[0])
+                    } else {
+                        f(&mut high[0], &mut low[idx_b])
+                    }
+                },
+            );
+        }
+        let cache_b = get_block_cache(block_b as usize, Arc::clone(&fs.block_device));
+        // 编号小的 inode 所在的块先加锁
+        let (mut guard_a, mut guard_b) = if a < b {
+            let guard_a = cache_a.lock();
+            (guard_a, cache_b.lock())
+        } else {
+            let guard_b = cache_b.lock();
+            (cache_a.lock(), guard_b)
+        };
+        f(guard_a.get_mut(offset_a), guard_b.get_mut(offset_b))
+    }
+
+    /// Remove the directory entry at `idx` by moving the last entry into its slot
+    fn remove_dirent(
+        &self,
+        idx: usize,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> Result<(), EfsError> {
+        let file_count = (dir_inode.size as usize) / DIRENT_SZ;
+        if idx + 1 < file_count {
+            let mut dirent = DirEntry::empty();
+            dir_inode.read_at(
+                (file_count - 1) * DIRENT_SZ,
+                dirent.as_bytes_mut(),
                 &self.block_device,
             );
+            dir_inode.write_at(idx * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+        }
+        let new_size = ((file_count - 1) * DIRENT_SZ) as u32;
+        let data_blocks_dealloc = dir_inode.decrease_size(new_size, &self.block_device);
+        ensure!(
+            fs.dealloc_data_blocks(&data_blocks_dealloc),
+            EfsError::Corrupt
+        );
+        Ok(())
+    }
+
+    /// Limit the blocks used by current directory and its immediate children,
+    /// 0 means no limit
+    pub fn set_quota(&self, max_blocks: u32) {
+        let fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            disk_inode.quota = max_blocks;
         });
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
         block_cache_sync_all();
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+        self.sync_metadata(&fs);
+    }
+
+    /// Whether current inode is protected from writes, truncation and unlinking
+    pub fn is_immutable(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_immutable())
+    }
+
+    /// Protect current inode from writes, truncation and unlinking, or lift the protection
+    pub fn set_immutable(&self, immutable: bool) {
+        let fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| disk_inode.set_immutable(immutable));
+        block_cache_sync_all();
+        self.sync_metadata(&fs);
+    }
+
+    /// Check that the quotas of current inode and of the directory it was
+    /// found in both allow `blocks` more blocks. For a handle that wasn't
+    /// found in a directory, every directory with a quota listing it is checked
+    fn check_quota(&self, blocks: u32, fs: &MutexGuard<EasyFileSystem>) -> Result<(), EfsError> {
+        if blocks == 0 {
+            return Ok(());
+        }
+        let mut dirs = vec![(self.block_id, self.block_offset)];
+        match self.parent {
+            Some(parent) => dirs.push(parent),
+            // 不知道所在目录的句柄（例如用 Inode::new 直接打开）要找出限制它的目录，否则可以绕过配额
+            None => dirs
+                .extend(fs.quota_parents(fs.get_inode_id(self.block_id as u32, self.block_offset))),
+        }
+        for (block_id, block_offset) in dirs {
+            let (quota, mut used, children) =
+                get_block_cache(block_id, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(block_offset, |dir_inode: &DiskInode| {
+                        if !dir_inode.is_dir() || dir_inode.quota == 0 {
+                            return (0, 0, Vec::new());
+                        }
+                        (
+                            dir_inode.quota,
+                            dir_inode.mapped_blocks(),
+                            self.child_inode_ids(dir_inode),
+                        )
+                    });
+            if quota == 0 {
+                continue;
+            }
+            for inode_id in children {
+                let (child_block_id, child_block_offset) = fs.get_disk_inode_pos(inode_id);
+                used += get_block_cache(child_block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(child_block_offset, |child: &DiskInode| {
+                        child.mapped_blocks()
+                    });
+            }
+            if used + blocks > quota {
+                return Err(EfsError::QuotaExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    /// Inode numbers of all entries of a directory
+    fn child_inode_ids(&self, dir_inode: &DiskInode) -> Vec<u32> {
+        let file_count = (dir_inode.size as usize) / DIRENT_SZ;
+        let mut dirent = DirEntry::empty();
+        (0..file_count)
+            .filter_map(|i| {
+                dir_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                (!matches!(dirent.name_bytes(), b"." | b"..")).then(|| dirent.inode_number())
+            })
+            .collect()
+    }
+
+    /// Flush the inode block and the index blocks of current inode to the
+    /// device if the filesystem is set to sync metadata
+    fn sync_metadata(&self, fs: &EasyFileSystem) {
+        Self::sync_inode_metadata(fs.get_inode_id(self.block_id as u32, self.block_offset), fs);
+    }
+
+    // 同 sync_metadata，用于只知道编号的 inode，例如目录操作涉及的子节点
+    fn sync_inode_metadata(inode_id: u32, fs: &EasyFileSystem) {
+        if !fs.is_sync_metadata() {
+            return;
+        }
+        let (block_id, _) = fs.get_disk_inode_pos(inode_id);
+        let mut block_ids: Vec<usize> = Self::read_inode(inode_id, fs, |disk_inode| {
+            disk_inode
+                .index_block_ids(&fs.block_device)
+                .into_iter()
+                .map(|block_id| block_id as usize)
+                .collect()
+        });
+        block_ids.push(block_id as usize);
+        // 块缓存已经写回，这里补上还没写回的块并让设备把它们落盘
+        checkpoint(&fs.block_device, &block_ids);
     }
 
     /// Increase the size of a disk inode
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size as u32 {
+    /// `new_blocks` are allocated beforehand with `alloc_data_blocks`, so that
+    /// running out of space is found before anything is changed
+    fn increase_size(&self, new_size: u32, disk_inode: &mut DiskInode, new_blocks: Vec<u32>) {
+        if new_size < disk_inode.size {
             return;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        let old_data_blocks = disk_inode.data_blocks();
+        disk_inode.increase_size(new_size, new_blocks, &self.block_device);
+        // 空闲的数据块全为 0，为新的末尾块直接建立清零的缓存，
+        // 之后向其中追加目录项等不足一块的写入不必先从设备读出
+        let data_blocks = disk_inode.data_blocks();
+        if data_blocks > old_data_blocks {
+            let block_id = disk_inode.get_block_id(data_blocks - 1, &self.block_device);
+            get_block_cache_zeroed(block_id as usize, Arc::clone(&self.block_device));
+        }
+    }
+
+    /// Release all the blocks of current inode and return them,
+    /// or only return them in dry-run mode
+    pub fn clear(&self) -> ReleasePlan {
+        check_corrupt(self.try_clear()).unwrap_or_default()
+    }
+
+    /// Like `clear`, but fail with `EfsError::Immutable` for an immutable inode
+    pub fn try_clear(&self) -> Result<ReleasePlan, EfsError> {
+        let mut fs = self.fs.lock();
+        self.clear_locked(&mut fs)
+    }
+
+    fn clear_locked(&self, fs: &mut MutexGuard<EasyFileSystem>) -> Result<ReleasePlan, EfsError> {
+        if self.read_disk_inode(|disk_inode| disk_inode.is_immutable()) {
+            return Err(EfsError::Immutable);
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        if fs.is_dry_run() {
+            let blocks = self.read_disk_inode(|disk_inode| {
+                if self.validate(disk_inode).is_err() {
+                    return Vec::new();
+                }
+                let mut blocks = disk_inode.data_block_ids(&self.block_device);
+                blocks.extend(disk_inode.index_block_ids(&self.block_device));
+                blocks
+            });
+            return Ok(ReleasePlan {
+                inodes: Vec::new(),
+                blocks,
+            });
+        }
+        let blocks = self.modify_disk_inode(|disk_inode| {
+            if self.validate(disk_inode).is_err() {
+                return Ok(Vec::new());
+            }
+            let mapped_blocks = disk_inode.mapped_blocks();
+            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+            // 打过洞的文件释放的块更少
+            ensure!(
+                data_blocks_dealloc.len() <= mapped_blocks as usize,
+                EfsError::Corrupt
+            );
+            ensure!(
+                fs.dealloc_data_blocks(&data_blocks_dealloc),
+                EfsError::Corrupt
+            );
+            Ok(data_blocks_dealloc)
+        })?;
+        block_cache_try_sync_all()?;
+        self.sync_metadata(fs);
+        Ok(ReleasePlan {
+            inodes: Vec::new(),
+            blocks,
+        })
     }
 
-    pub fn clear(&self) {
+    /// Deallocate the data blocks entirely within `[offset, offset + len)` and zero
+    /// the rest of the range, like `fallocate(FALLOC_FL_PUNCH_HOLE)`. The size
+    /// doesn't change and the range reads back as zeros; writing into it allocates again.
+    /// Fail if current inode is a directory or immutable, or if a released block
+    /// is already free.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<(), EfsError> {
         let mut fs = self.fs.lock();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+            self.validate(disk_inode)?;
+            if !disk_inode.is_file() {
+                return Err(EfsError::IsDir);
+            }
+            if disk_inode.is_immutable() {
+                return Err(EfsError::Immutable);
             }
+            let start = offset.min(disk_inode.size as u64) as usize;
+            let end = offset.saturating_add(len).min(disk_inode.size as u64) as usize;
+            let data_blocks_dealloc = disk_inode.punch_hole(start, end, &self.block_device);
+            ensure!(
+                fs.dealloc_data_blocks(&data_blocks_dealloc),
+                EfsError::Corrupt
+            );
+            Ok(())
+        })?;
+        let synced = block_cache_try_sync_all();
+        self.sync_metadata(&fs);
+        synced
+    }
+
+    /// Allocate the data blocks needed for `size` bytes without changing the
+    /// size of current inode, later writes within it don't allocate. Either all
+    /// of the blocks are allocated or nothing is reserved.
+    pub fn reserve(&self, size: u64) -> Result<(), EfsError> {
+        if size > MAX_FILE_SIZE as u64 {
+            return Err(EfsError::NoSpace);
+        }
+        let size = size as u32;
+        let mut fs = self.fs.lock();
+        let blocks_needed = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            if disk_inode.is_immutable() {
+                return Err(EfsError::Immutable);
+            }
+            // 内联的文件没有映射的数据块，但 size 不为 0
+            Ok(if size > disk_inode.size.max(disk_inode.mapped_size()) {
+                disk_inode.blocks_num_needed(size)
+            } else {
+                0
+            })
+        })?;
+        if blocks_needed == 0 {
+            return Ok(());
+        }
+        self.check_quota(blocks_needed, &fs)?;
+        if fs.free_data_blocks() < blocks_needed as usize {
+            return Err(EfsError::NoSpace);
+        }
+        let new_blocks = fs
+            .alloc_data_blocks(blocks_needed as usize)
+            .ok_or(EfsError::NoSpace)?;
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.reserve(size, new_blocks, &self.block_device);
         });
-        block_cache_sync_all();
+        block_cache_try_sync_all()?;
+        self.sync_metadata(&fs);
+        Ok(())
+    }
+
+    /// Ids of the data blocks of current inode in file order
+    pub fn data_block_ids(&self) -> Vec<u32> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.data_block_ids(&self.block_device))
+    }
+
+    /// Ids of the index blocks of current inode
+    pub fn index_block_ids(&self) -> Vec<u32> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.index_block_ids(&self.block_device))
     }
 
     /// Read data from current inode
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        check_corrupt(self.try_read_at(offset, buf)).unwrap_or(0)
+    }
+    /// Same as `read_at`, but fail with `EfsError::Corrupt` if a block pointer
+    /// of current inode lies outside of the data area instead of reading it
+    pub fn try_read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, EfsError> {
+        // 读取范围在文件末尾之后时不必访问数据块
+        if buf.is_empty() || offset as u64 >= self.len() {
+            return Ok(0);
+        }
+        let fs = self.fs.lock();
+        let window = if self.last_read_end.load(Ordering::Relaxed) == offset {
+            fs.readahead_window()
+        } else {
+            0
+        };
+        let data_area = fs.data_area();
+        let (size, ahead) = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            let size = disk_inode.try_read_at(offset, buf, &data_area, &self.block_device)?;
+            // 顺序读时预读读取结束之后的若干数据块，跳过空洞
+            let first = (offset + size).div_ceil(BLOCK_SZ) as u32;
+            let end = (disk_inode.size as usize).div_ceil(BLOCK_SZ) as u32;
+            let ahead: Vec<usize> = (first..end.min(first.saturating_add(window as u32)))
+                .map_while(|inner_id| {
+                    disk_inode
+                        .try_get_block_id(inner_id, &data_area, &self.block_device)
+                        .ok()
+                })
+                .filter(|&block_id| block_id != 0)
+                .map(|block_id| block_id as usize)
+                .collect();
+            Ok((size, ahead))
+        })?;
+        prefetch_blocks(&ahead, &self.block_device);
+        self.last_read_end.store(offset + size, Ordering::Relaxed);
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        trace(|| TraceEvent::Read {
+            inode: fs.get_inode_id(self.block_id as u32, self.block_offset),
+            offset,
+            len: size,
+        });
+        Ok(size)
+    }
+    /// Read data from current inode into several buffers in order, e.g. the
+    /// segments of a DMA scatter list, return the total number of bytes read
+    pub fn read_at_scatter(&self, offset: usize, bufs: &mut [&mut [u8]]) -> usize {
+        let fs = self.fs.lock();
+        let data_area = fs.data_area();
+        let size = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            disk_inode.read_at_scatter(offset, bufs, &data_area, &self.block_device)
+        });
+        let size = check_corrupt(size).unwrap_or(0);
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        trace(|| TraceEvent::Read {
+            inode: fs.get_inode_id(self.block_id as u32, self.block_offset),
+            offset,
+            len: size,
+        });
+        size
     }
     /// Write data to current inode
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|mut disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, &mut disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+        check_corrupt(self.try_write_at(offset, buf)).unwrap_or(0)
+    }
+    /// Write data to current inode, fail if the blocks it needs exceed a quota,
+    /// the inode is immutable or a block pointer lies outside of the data area
+    pub fn try_write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, EfsError> {
+        self.write_at_with(offset, buf, false)
+    }
+    /// Read data from current inode, bypassing the block cache for the whole data
+    /// blocks which are not resident, e.g. to copy a huge file without evicting
+    /// hot metadata. See `DiskInode::read_at_direct`.
+    pub fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let fs = self.fs.lock();
+        let data_area = fs.data_area();
+        let size = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            disk_inode.read_at_direct(offset, buf, &data_area, &self.block_device)
+        });
+        let size = check_corrupt(size).unwrap_or(0);
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        trace(|| TraceEvent::Read {
+            inode: fs.get_inode_id(self.block_id as u32, self.block_offset),
+            offset,
+            len: size,
         });
-        block_cache_sync_all();
         size
     }
+    /// Write data to current inode, bypassing the block cache for the whole data
+    /// blocks which are not resident. The inode and index blocks still go through
+    /// the cache. See `DiskInode::write_at_direct`.
+    pub fn write_at_direct(&self, offset: usize, buf: &[u8]) -> usize {
+        check_corrupt(self.try_write_at_direct(offset, buf)).unwrap_or(0)
+    }
+    /// Same as `write_at_direct`, but fail like `try_write_at`
+    pub fn try_write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize, EfsError> {
+        self.write_at_with(offset, buf, true)
+    }
+    fn write_at_with(&self, offset: usize, buf: &[u8], direct: bool) -> Result<usize, EfsError> {
+        // 空写入不访问缓存和分配器，也不会把文件扩展到 offset
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // a write past the max file size is refused as a whole
+        let new_size = match offset.checked_add(buf.len()) {
+            Some(new_size) if new_size <= MAX_FILE_SIZE => new_size as u32,
+            _ => return Ok(0),
+        };
+        let mut fs = self.fs.lock();
+        let data_area = fs.data_area();
+        let (blocks_needed, holes) = self.read_disk_inode(|disk_inode| {
+            self.validate(disk_inode)?;
+            if disk_inode.is_immutable() {
+                return Err(EfsError::Immutable);
+            }
+            let blocks_needed = if new_size > disk_inode.size {
+                // 扩展时沿着最后一个数据块的索引路径追加，先检查这条路径
+                if let Some(last) = disk_inode.data_blocks().checked_sub(1) {
+                    disk_inode.try_get_block_id(last, &data_area, &self.block_device)?;
+                }
+                disk_inode.blocks_num_needed(new_size)
+            } else {
+                0
+            };
+            let holes =
+                disk_inode.holes(offset, new_size as usize, &data_area, &self.block_device)?;
+            Ok((blocks_needed, holes))
+        })?;
+        // 配额按映射的大小计算，已经包含了空洞
+        self.check_quota(blocks_needed, &fs)?;
+        if fs.free_data_blocks() < blocks_needed as usize + holes.len() {
+            return Err(EfsError::NoSpace);
+        }
+        // 一次分配好扩展和填洞需要的所有块，中途用完时已分配的块会被释放
+        let mut new_blocks = fs
+            .alloc_data_blocks(blocks_needed as usize + holes.len())
+            .ok_or(EfsError::NoSpace)?;
+        let hole_blocks = new_blocks.split_off(blocks_needed as usize);
+        let size = self.modify_disk_inode(|disk_inode| {
+            // 直接写入时不为新的末尾块建立缓存
+            if direct && new_size > disk_inode.size {
+                disk_inode.increase_size(new_size, new_blocks, &self.block_device);
+            } else {
+                self.increase_size(new_size, disk_inode, new_blocks);
+            }
+            // 写入范围内被打洞的块重新分配数据块
+            for (inner_id, block_id) in holes.into_iter().zip(hole_blocks) {
+                disk_inode.set_block_id(inner_id, block_id, &self.block_device);
+            }
+            if direct {
+                disk_inode.write_at_direct(offset, buf, &data_area, &self.block_device)
+            } else {
+                disk_inode.try_write_at(offset, buf, &data_area, &self.block_device)
+            }
+        })?;
+        block_cache_try_sync_all()?;
+        self.sync_metadata(&fs);
+        self.bytes_written.fetch_add(size, Ordering::Relaxed);
+        trace(|| TraceEvent::Write {
+            inode: fs.get_inode_id(self.block_id as u32, self.block_offset),
+            offset,
+            len: size,
+        });
+        Ok(size)
+    }
+    /// Read the whole content of current inode into a new `Vec`
+    pub fn read_all(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.len() as usize];
+        let size = self.read_at(0, &mut buf);
+        buf.truncate(size);
+        buf
+    }
+    /// CRC-32 (IEEE) of the content of current inode, holes count as zeros.
+    /// The data is read one block at a time through the block cache.
+    pub fn checksum(&self) -> u32 {
+        let mut buf = [0u8; BLOCK_SZ];
+        let mut offset = 0;
+        let mut crc = !0u32;
+        loop {
+            let len = self.read_at(offset, &mut buf);
+            if len == 0 {
+                return !crc;
+            }
+            crc = crc32_update(crc, &buf[..len]);
+            offset += len;
+        }
+    }
+    /// Byte offset of the first occurrence of `needle` in the content of current inode.
+    /// The data is read one block at a time, matches may straddle block boundaries.
+    pub fn find_bytes(&self, needle: &[u8]) -> Option<u64> {
+        if needle.is_empty() {
+            return Some(0);
+        }
+        let mut buf = [0u8; BLOCK_SZ];
+        let mut offset = 0;
+        // 保留上一块末尾不足一个 needle 的字节，和下一块拼起来查找
+        let mut window: Vec<u8> = Vec::with_capacity(BLOCK_SZ + needle.len());
+        let mut window_start = 0u64;
+        loop {
+            let len = self.read_at(offset, &mut buf);
+            if len == 0 {
+                return None;
+            }
+            window.extend_from_slice(&buf[..len]);
+            if let Some(pos) = window.windows(needle.len()).position(|w| w == needle) {
+                return Some(window_start + pos as u64);
+            }
+            let consumed = window.len().saturating_sub(needle.len() - 1);
+            window.drain(..consumed);
+            window_start += consumed as u64;
+            offset += len;
+        }
+    }
+    /// Replace the content of current inode with `data`, the inverse of `read_all`.
+    /// Fail with `EfsError::NoSpace` before the old content is released if `data`
+    /// won't fit in the blocks it frees and the free ones.
+    pub fn write_all(&self, data: &[u8]) -> Result<(), EfsError> {
+        if data.len() > MAX_FILE_SIZE {
+            return Err(EfsError::NoSpace);
+        }
+        {
+            let fs = self.fs.lock();
+            let (released, needed) = self.read_disk_inode(|disk_inode| {
+                self.validate(disk_inode)?;
+                let released = disk_inode.blocks_released_by(0, &self.block_device).len();
+                Ok((
+                    released,
+                    DiskInode::total_blocks(data.len() as u32) as usize,
+                ))
+            })?;
+            self.check_quota(needed.saturating_sub(released) as u32, &fs)?;
+            if fs.free_data_blocks() + released < needed {
+                return Err(EfsError::NoSpace);
+            }
+        }
+        self.try_clear()?;
+        if self.try_write_at(0, data)? < data.len() {
+            return Err(EfsError::NoSpace);
+        }
+        Ok(())
+    }
+}
+
+/// Handles are equal when they refer to the same disk inode of the same filesystem
+impl PartialEq for Inode {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.fs, &other.fs)
+            && (self.block_id, self.block_offset) == (other.block_id, other.block_offset)
+    }
+}
+
+impl Eq for Inode {}
+
+impl Hash for Inode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.block_id, self.block_offset).hash(state);
+    }
+}
+
+impl Drop for Inode {
+    /// Write back its own dirty blocks when the last handle of an inode goes away,
+    /// or leave the inode to be released if it has been unlinked
+    fn drop(&mut self) {
+        let key = self.open_key();
+        let unlinked = {
+            let mut open_inodes = open_inodes(&key).lock();
+            let open = open_inodes.get_mut(&key).unwrap();
+            open.handles -= 1;
+            if open.handles > 0 {
+                return;
+            }
+            open_inodes.remove(&key).unwrap().unlinked
+        };
+        if unlinked {
+            // 调用者可能正锁着文件系统，这里加锁会死锁
+            ORPHANS.lock().push(key);
+            return;
+        }
+        // 只写回这个 inode 自己的块：inode 所在的块以及它映射的索引块和数据块，
+        // 其他文件的脏块仍由块缓存按需写回。句柄过期时这些块已经不属于它了
+        let mut block_ids = vec![self.block_id];
+        self.read_disk_inode(|disk_inode| {
+            if self.validate(disk_inode).is_ok() {
+                disk_inode.walk_mapping(
+                    |_| true,
+                    &self.block_device,
+                    |block_id, _| block_ids.push(block_id as usize),
+                );
+            }
+        });
+        sync_blocks(&self.block_device, &block_ids);
+    }
+}
+
+// 按字节查表计算 CRC-32，多项式取反射形式 0xEDB88320
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            k += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
 }
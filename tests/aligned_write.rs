@@ -0,0 +1,79 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem, BLOCK_SZ,
+};
+use std::sync::{Arc, Mutex};
+
+// 记录读过的块号
+struct ReadLog {
+    inner: Arc<MemDev>,
+    reads: Mutex<Vec<usize>>,
+}
+
+impl BlockDevice for ReadLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn aligned_writes_read_no_data_blocks() {
+    let dev = Arc::new(ReadLog {
+        inner: MemDev::new(4096),
+        reads: Mutex::new(Vec::new()),
+    });
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    // 先让位图和 inode 所在的块驻留在缓存中
+    f.write_at(0, &[1u8; 3 * BLOCK_SZ]);
+    f.clear();
+    dev.reads.lock().unwrap().clear();
+    assert_eq!(f.write_at(0, &[2u8; 8 * BLOCK_SZ]), 8 * BLOCK_SZ);
+    assert_eq!(*dev.reads.lock().unwrap(), []);
+    // 数据块不驻留时覆盖写整块也不读出数据块
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    let data_blocks = f.data_block_ids();
+    dev.reads.lock().unwrap().clear();
+    assert_eq!(f.write_at(BLOCK_SZ, &[3u8; 4 * BLOCK_SZ]), 4 * BLOCK_SZ);
+    let reads = dev.reads.lock().unwrap().clone();
+    for block_id in data_blocks {
+        assert!(!reads.contains(&(block_id as usize)), "{:?}", reads);
+    }
+    let mut buf = [0u8; 8 * BLOCK_SZ];
+    assert_eq!(f.read_at(0, &mut buf), 8 * BLOCK_SZ);
+    assert!(buf[..BLOCK_SZ].iter().all(|&byte| byte == 2));
+    assert!(buf[BLOCK_SZ..5 * BLOCK_SZ].iter().all(|&byte| byte == 3));
+    assert!(buf[5 * BLOCK_SZ..].iter().all(|&byte| byte == 2));
+}
+
+#[test]
+fn unaligned_writes_read_the_partial_blocks() {
+    let dev = Arc::new(ReadLog {
+        inner: MemDev::new(4096),
+        reads: Mutex::new(Vec::new()),
+    });
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    let f = EasyFileSystem::root_inode(&efs).create("f").unwrap();
+    f.write_at(0, &[1u8; 4 * BLOCK_SZ]);
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    let data_blocks = f.data_block_ids();
+    dev.reads.lock().unwrap().clear();
+    f.write_at(BLOCK_SZ / 2, &[2u8; 2 * BLOCK_SZ]);
+    let reads = dev.reads.lock().unwrap().clone();
+    // 首尾两个不完整的块要先读出，中间的整块不读
+    assert!(reads.contains(&(data_blocks[0] as usize)));
+    assert!(!reads.contains(&(data_blocks[1] as usize)));
+    assert!(reads.contains(&(data_blocks[2] as usize)));
+    assert!(!reads.contains(&(data_blocks[3] as usize)));
+}
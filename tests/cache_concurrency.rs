@@ -0,0 +1,72 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, flush_dirty_sorted, BlockDevice, EasyFileSystem, BLOCK_CACHE_MANAGER,
+    BLOCK_SZ,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn sync_racing_with_get_and_eviction() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    // 容量很小，查找时不断替换，与同步争用管理器和块缓存的锁
+    BLOCK_CACHE_MANAGER.lock().set_capacity(24);
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    let f = EasyFileSystem::root_inode(&efs).create("f").unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let f = Arc::clone(&f);
+        thread::spawn(move || {
+            // 7 与 60 互素，60 次写入覆盖前 60 个块
+            for i in 0..60usize {
+                let offset = (i * 7 % 60) * BLOCK_SZ;
+                assert_eq!(f.write_at(offset, &[i as u8; 700]), 700);
+            }
+        })
+    };
+    let reader = {
+        let block_device = block_device.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            let mut block_id = 0;
+            while !done.load(Ordering::Relaxed) {
+                let cache = BLOCK_CACHE_MANAGER
+                    .lock()
+                    .get_block_cache(block_id % 4096, block_device.clone());
+                cache.lock().read(0, |data: &[u8; 8]| *data);
+                block_id += 37;
+                thread::yield_now();
+            }
+        })
+    };
+    let syncer = {
+        let block_device = block_device.clone();
+        let done = Arc::clone(&done);
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                block_cache_try_sync_all().unwrap();
+                flush_dirty_sorted();
+                BLOCK_CACHE_MANAGER.lock().export_warm(&block_device);
+                thread::yield_now();
+            }
+        })
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        writer.join().unwrap();
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+        syncer.join().unwrap();
+        tx.send(()).unwrap();
+    });
+    if rx.recv_timeout(Duration::from_secs(60)).is_err() {
+        panic!("deadlock: not finished within 60 seconds");
+    }
+    block_cache_try_sync_all().unwrap();
+    assert_eq!(f.len(), (59 * BLOCK_SZ + 700) as u64);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockDevice, EasyFileSystem, BLOCK_CACHE_MANAGER, BLOCK_SZ};
+use std::sync::Arc;
+
+// 绕过文件接口修改一个数据块，块缓存中留下一个脏块
+fn dirty_block(dev: &Arc<MemDev>, block_id: u32, byte: u8) {
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(block_id as usize, block_device);
+    cache
+        .lock()
+        .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(byte));
+}
+
+fn on_disk(dev: &MemDev, block_id: u32) -> u8 {
+    dev.data.lock().unwrap()[block_id as usize * BLOCK_SZ]
+}
+
+#[test]
+fn dropping_last_handle_writes_back_only_own_blocks() {
+    let dev = MemDev::new(4096);
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[1; 40 * BLOCK_SZ]).unwrap();
+    let g = root.create("g").unwrap();
+    g.write_all(&[1; BLOCK_SZ]).unwrap();
+    let f_block = *f.data_block_ids().last().unwrap();
+    let g_block = g.data_block_ids()[0];
+    dirty_block(&dev, f_block, 2);
+    dirty_block(&dev, g_block, 2);
+    // 还有别的句柄时不写回
+    let f2 = root.find("f").unwrap();
+    drop(f);
+    assert_eq!(on_disk(&dev, f_block), 1);
+    drop(f2);
+    assert_eq!(on_disk(&dev, f_block), 2);
+    assert_eq!(on_disk(&dev, g_block), 1);
+    drop(g);
+    assert_eq!(on_disk(&dev, g_block), 2);
+}
@@ -0,0 +1,26 @@
+// 测试共用的内存块设备
+use easy_fs::{BlockDevice, BLOCK_SZ};
+use std::sync::{Arc, Mutex};
+
+pub struct MemDev {
+    pub data: Mutex<Vec<u8>>,
+}
+
+impl MemDev {
+    pub fn new(blocks: usize) -> Arc<Self> {
+        Arc::new(Self {
+            data: Mutex::new(vec![0; blocks * BLOCK_SZ]),
+        })
+    }
+}
+
+impl BlockDevice for MemDev {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let data = self.data.lock().unwrap();
+        buf.copy_from_slice(&data[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut data = self.data.lock().unwrap();
+        data[block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ].copy_from_slice(buf);
+    }
+}
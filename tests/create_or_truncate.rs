@@ -0,0 +1,46 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{EasyFileSystem, BLOCK_SZ};
+
+#[test]
+fn creates_missing_file() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create_or_truncate("f").unwrap();
+    assert!(!f.is_dir());
+    assert_eq!(f.len(), 0);
+    assert_eq!(root.ls().iter().filter(|name| *name == "f").count(), 1);
+}
+
+#[test]
+fn truncates_existing_file() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().free_data_blocks();
+    let free_inodes = efs.lock().free_inodes();
+    let old = root.create("f").unwrap();
+    old.write_all(&[9; 40 * BLOCK_SZ]).unwrap();
+    let f = root.create_or_truncate("f").unwrap();
+    // 截断的是同一个 inode，已打开的句柄也看到空文件
+    assert_eq!(f.len(), 0);
+    assert_eq!(old.len(), 0);
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks);
+    assert_eq!(efs.lock().free_inodes(), free_inodes - 1);
+    assert_eq!(root.ls().iter().filter(|name| *name == "f").count(), 1);
+    f.write_all(b"new").unwrap();
+    assert_eq!(old.read_all(), b"new");
+}
+
+#[test]
+fn refuses_directory() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    d.create("inner").unwrap().write_all(b"data").unwrap();
+    assert!(root.create_or_truncate("d").is_none());
+    assert_eq!(d.find("inner").unwrap().read_all(), b"data");
+    // 普通文件下不能创建
+    let f = root.create("f").unwrap();
+    assert!(f.create_or_truncate("g").is_none());
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem, Inode,
+};
+use std::sync::{Arc, Mutex};
+
+// 记录读过的块号
+struct ReadLog {
+    inner: Arc<MemDev>,
+    reads: Mutex<Vec<usize>>,
+}
+
+impl BlockDevice for ReadLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+// 缓存全部失效后执行 f，返回它从设备读取的块数
+fn cold_reads(dev: &Arc<ReadLog>, f: impl FnOnce()) -> usize {
+    block_cache_try_sync_all().unwrap();
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    invalidate_device_cache(&block_device);
+    dev.reads.lock().unwrap().clear();
+    f();
+    dev.reads.lock().unwrap().len()
+}
+
+fn big_dir(dev: &Arc<ReadLog>) -> Arc<Inode> {
+    let efs = EasyFileSystem::create(dev.clone(), 8192, 1);
+    let d = EasyFileSystem::root_inode(&efs).mkdir("d").unwrap();
+    let names: Vec<String> = (0..300).map(|i| format!("f{}", i)).collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    assert!(d.create_batch(&names).iter().all(Option::is_some));
+    d
+}
+
+#[test]
+fn create_unchecked_reads_fewer_blocks() {
+    let dev = Arc::new(ReadLog {
+        inner: MemDev::new(8192),
+        reads: Mutex::new(Vec::new()),
+    });
+    let d = big_dir(&dev);
+    let checked = cold_reads(&dev, || {
+        d.create("a").unwrap();
+    });
+    let unchecked = cold_reads(&dev, || {
+        d.create_unchecked("b").unwrap();
+    });
+    // 检查重名要读遍目录的所有数据块，跳过检查只读写入位置所在的块
+    assert!(unchecked + 10 < checked, "{} vs {}", unchecked, checked);
+    assert_eq!(d.ls().len(), 2 + 302);
+}
+
+#[test]
+fn create_still_rejects_duplicates() {
+    let dev = Arc::new(ReadLog {
+        inner: MemDev::new(8192),
+        reads: Mutex::new(Vec::new()),
+    });
+    let d = big_dir(&dev);
+    assert!(d.create("f0").is_none());
+    assert!(d.create("f299").is_none());
+    assert_eq!(d.ls().len(), 2 + 300);
+}
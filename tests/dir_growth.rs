@@ -0,0 +1,66 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{block_cache_try_sync_all, BlockDevice, EasyFileSystem, BLOCK_SZ};
+use std::sync::{Arc, Mutex};
+
+// 记录读过的块号
+struct ReadLog {
+    inner: Arc<MemDev>,
+    reads: Mutex<Vec<usize>>,
+}
+
+impl BlockDevice for ReadLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn growing_a_directory_reads_no_new_block() {
+    let dev = Arc::new(ReadLog {
+        inner: MemDev::new(8192),
+        reads: Mutex::new(Vec::new()),
+    });
+    let efs = EasyFileSystem::create(dev.clone(), 8192, 1);
+    let d = EasyFileSystem::root_inode(&efs).mkdir("d").unwrap();
+    let mut created = 0;
+    let mut grown = 0;
+    // 增长足够多次，目录也会用到新的索引块
+    while grown < 40 {
+        let data_blocks = d.data_block_ids();
+        let index_blocks = d.index_block_ids();
+        dev.reads.lock().unwrap().clear();
+        let name = format!("f{}", created);
+        d.create(&name).unwrap();
+        created += 1;
+        let grown_blocks = d.data_block_ids();
+        if grown_blocks.len() == data_blocks.len() {
+            continue;
+        }
+        grown += 1;
+        let reads = dev.reads.lock().unwrap().clone();
+        let new_block = *grown_blocks.last().unwrap() as usize;
+        assert!(!reads.contains(&new_block), "new block {} read", new_block);
+        for block_id in d.index_block_ids() {
+            if !index_blocks.contains(&block_id) {
+                assert!(
+                    !reads.contains(&(block_id as usize)),
+                    "new index block {} read",
+                    block_id
+                );
+            }
+        }
+        // 新块没有读过，同步后它的内容写到了设备上
+        block_cache_try_sync_all().unwrap();
+        let data = dev.inner.data.lock().unwrap();
+        assert!(data[new_block * BLOCK_SZ..(new_block + 1) * BLOCK_SZ]
+            .windows(name.len())
+            .any(|window| window == name.as_bytes()));
+    }
+    assert_eq!(d.ls().len(), created + 2);
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockDevice, EasyFileSystem, BLOCK_SZ};
+use std::sync::{Arc, Mutex};
+
+// 记录 discard 调用的设备
+struct DiscardLog {
+    inner: Arc<MemDev>,
+    discards: Mutex<Vec<(usize, usize)>>,
+}
+
+impl BlockDevice for DiscardLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.inner.write_block(block_id, buf)
+    }
+    fn discard(&self, block_id: usize, count: usize) {
+        self.discards.lock().unwrap().push((block_id, count));
+    }
+}
+
+// 取出记录的调用，检查它们是互不相邻的连续块段，返回其中的所有块
+fn take_discarded(dev: &DiscardLog) -> Vec<u32> {
+    let mut discards = core::mem::take(&mut *dev.discards.lock().unwrap());
+    discards.sort_unstable();
+    for pair in discards.windows(2) {
+        assert!(pair[0].0 + pair[0].1 < pair[1].0, "{:?}", discards);
+    }
+    discards
+        .iter()
+        .flat_map(|&(start, count)| (start..start + count).map(|block_id| block_id as u32))
+        .collect()
+}
+
+fn log_device() -> Arc<DiscardLog> {
+    Arc::new(DiscardLog {
+        inner: MemDev::new(4096),
+        discards: Mutex::new(Vec::new()),
+    })
+}
+
+#[test]
+fn unlink_discards_freed_blocks_in_runs() {
+    let dev = log_device();
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[1; 60 * BLOCK_SZ]).unwrap();
+    let mut blocks = f.data_block_ids();
+    blocks.extend(f.index_block_ids());
+    blocks.sort_unstable();
+    drop(f);
+    take_discarded(&dev);
+    let free_blocks = efs.lock().free_data_blocks();
+    root.unlink("f").unwrap();
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks + blocks.len());
+    assert_eq!(take_discarded(&dev), blocks);
+    // 清零的块已经写回，之后的同步不会覆盖掉 TRIM
+    assert!(
+        dev.inner.data.lock().unwrap()[blocks[0] as usize * BLOCK_SZ..][..BLOCK_SZ]
+            .iter()
+            .all(|&b| b == 0)
+    );
+}
+
+#[test]
+fn punch_hole_discards_unmapped_blocks() {
+    let dev = log_device();
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[1; 8 * BLOCK_SZ]).unwrap();
+    let blocks = f.data_block_ids();
+    take_discarded(&dev);
+    f.punch_hole(2 * BLOCK_SZ as u64, 4 * BLOCK_SZ as u64)
+        .unwrap();
+    assert_eq!(take_discarded(&dev), blocks[2..6]);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockCacheManager, BlockDevice, BLOCK_SZ};
+use std::sync::Arc;
+
+// 丢弃写到块 0 的数据，写回校验总是失败
+struct LoseBlock0(Arc<MemDev>);
+
+impl BlockDevice for LoseBlock0 {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.0.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        if block_id != 0 {
+            self.0.write_block(block_id, buf)
+        }
+    }
+}
+
+fn block_prefix(dev: &MemDev, block_id: usize) -> [u8; 8] {
+    let data = dev.data.lock().unwrap();
+    data[block_id * BLOCK_SZ..block_id * BLOCK_SZ + 8]
+        .try_into()
+        .unwrap()
+}
+
+#[test]
+fn evicted_dirty_blocks_reach_the_device() {
+    let dev = MemDev::new(64);
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let mut manager = BlockCacheManager::new();
+    manager.set_capacity(4);
+    // 缓存装满不被别处持有的脏块
+    for block_id in 0..4 {
+        manager
+            .get_block_cache(block_id, block_device.clone())
+            .lock()
+            .modify(0, |data: &mut [u8; 8]| *data = [block_id as u8 + 1; 8]);
+    }
+    for block_id in 0..4 {
+        assert_eq!(block_prefix(&dev, block_id), [0; 8]);
+    }
+    for block_id in 4..8 {
+        manager.get_block_cache(block_id, block_device.clone());
+    }
+    assert_eq!(manager.evict_flushes(), 4);
+    for block_id in 0..4 {
+        assert!(!manager.is_resident(block_id, &block_device));
+        assert_eq!(block_prefix(&dev, block_id), [block_id as u8 + 1; 8]);
+    }
+    // 替换干净的块不写回，也不计数
+    for block_id in 8..12 {
+        manager.get_block_cache(block_id, block_device.clone());
+    }
+    assert_eq!(manager.evict_flushes(), 4);
+}
+
+#[test]
+fn dirty_block_failing_verification_is_not_evicted() {
+    let dev = MemDev::new(64);
+    let block_device: Arc<dyn BlockDevice> = Arc::new(LoseBlock0(dev.clone()));
+    let mut manager = BlockCacheManager::new();
+    manager.set_capacity(2);
+    manager.set_verify_writes(&block_device, Some(64));
+    for block_id in 0..2 {
+        manager
+            .get_block_cache(block_id, block_device.clone())
+            .lock()
+            .modify(0, |data: &mut [u8; 8]| *data = [block_id as u8 + 1; 8]);
+    }
+    // 块 0 写回校验失败，留在缓存中，改为替换块 1
+    manager.get_block_cache(2, block_device.clone());
+    assert!(manager.is_resident(0, &block_device));
+    assert!(!manager.is_resident(1, &block_device));
+    assert_eq!(manager.evict_flushes(), 1);
+    assert_eq!(block_prefix(&dev, 1), [2; 8]);
+    // 管理器丢弃时块 0 的缓存仍会尝试写回，不要再校验
+    manager.set_verify_writes(&block_device, None);
+}
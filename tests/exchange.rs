@@ -0,0 +1,98 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{EasyFileSystem, Inode};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// 两个线程反向交换 a/x 和 b/y，超时视为死锁
+fn reciprocal_exchanges(a: Arc<Inode>, b: Arc<Inode>) {
+    let (tx, rx) = mpsc::channel();
+    let handles: Vec<_> = [(a.clone(), b.clone()), (b.clone(), a.clone())]
+        .into_iter()
+        .map(|(from, to)| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let (name_from, name_to) = if from.find("x").is_some() {
+                        ("x", "y")
+                    } else {
+                        ("y", "x")
+                    };
+                    from.exchange(name_from, &to, name_to);
+                }
+                tx.send(()).unwrap();
+            })
+        })
+        .collect();
+    for _ in 0..2 {
+        if rx.recv_timeout(Duration::from_secs(30)).is_err() {
+            panic!("deadlock: exchanges not finished within 30 seconds");
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    // 每个目录里始终各有一项
+    assert_eq!(a.ls().len() + b.ls().len(), 2 * 3);
+    assert!(a.check_dir().is_ok() && b.check_dir().is_ok());
+}
+
+#[test]
+fn reciprocal_exchanges_in_one_inode_block() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let a = root.mkdir("a").unwrap();
+    let b = root.mkdir("b").unwrap();
+    a.create("x").unwrap();
+    b.mkdir("y").unwrap();
+    reciprocal_exchanges(a, b);
+}
+
+#[test]
+fn reciprocal_exchanges_across_inode_blocks() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let a = root.mkdir("a").unwrap();
+    let inodes_per_block = efs.lock().layout_info().inodes_per_block;
+    for i in 0..inodes_per_block {
+        root.create(&format!("pad{}", i)).unwrap();
+    }
+    let b = root.mkdir("b").unwrap();
+    let fs = efs.lock();
+    assert_ne!(
+        fs.get_disk_inode_pos(1).0,
+        fs.get_disk_inode_pos(inodes_per_block + 2).0
+    );
+    drop(fs);
+    a.create("x").unwrap();
+    b.mkdir("y").unwrap();
+    reciprocal_exchanges(a, b);
+}
+
+#[test]
+fn reciprocal_renames() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = Arc::new(EasyFileSystem::root_inode(&efs));
+    root.create("p").unwrap();
+    root.create("q").unwrap();
+    let (tx, rx) = mpsc::channel();
+    for (from, to) in [("p", "p2"), ("q", "q2")] {
+        let root = Arc::clone(&root);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for _ in 0..200 {
+                assert!(root.rename(from, to));
+                assert!(root.rename(to, from));
+            }
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..2 {
+        if rx.recv_timeout(Duration::from_secs(30)).is_err() {
+            panic!("deadlock: renames not finished within 30 seconds");
+        }
+    }
+    assert!(root.find("p").is_some() && root.find("q").is_some());
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "std")]
+
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    start_flusher, stop_flusher, BlockDevice, EasyFileSystem, BLOCK_CACHE_MANAGER, BLOCK_SZ,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 刷盘线程是全局的，本文件的测试依次运行
+static SERIAL: Mutex<()> = Mutex::new(());
+
+// 绕过文件接口修改一个数据块，块缓存中留下一个脏块
+fn dirty_block(dev: &Arc<MemDev>, block_id: u32, byte: u8) {
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(block_id as usize, block_device);
+    cache
+        .lock()
+        .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(byte));
+}
+
+fn on_disk(dev: &MemDev, block_id: u32) -> u8 {
+    dev.data.lock().unwrap()[block_id as usize * BLOCK_SZ]
+}
+
+fn file_block(dev: &Arc<MemDev>) -> (Arc<spin::Mutex<EasyFileSystem>>, u32) {
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let f = EasyFileSystem::root_inode(&efs).create("f").unwrap();
+    f.write_all(&[1; BLOCK_SZ]).unwrap();
+    let block_id = f.data_block_ids()[0];
+    (efs, block_id)
+}
+
+#[test]
+fn flusher_writes_back_without_sync() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let dev = MemDev::new(4096);
+    let (_efs, block_id) = file_block(&dev);
+    start_flusher(10);
+    dirty_block(&dev, block_id, 2);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while on_disk(&dev, block_id) != 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    stop_flusher();
+    assert_eq!(on_disk(&dev, block_id), 2);
+}
+
+#[test]
+fn stopping_flusher_flushes_once_more() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let dev = MemDev::new(4096);
+    let (_efs, block_id) = file_block(&dev);
+    // 间隔很长，写回只能来自停止时的最后一次刷盘
+    start_flusher(60_000);
+    dirty_block(&dev, block_id, 3);
+    assert_eq!(on_disk(&dev, block_id), 1);
+    stop_flusher();
+    assert_eq!(on_disk(&dev, block_id), 3);
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{EasyFileSystem, EfsError};
+
+#[test]
+fn handle_survives_rename_over() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_at(0, b"old");
+    root.create("g").unwrap();
+    // 被覆盖的 f 仍有打开的句柄，inode 留到句柄关闭后释放
+    assert!(root.rename("g", "f"));
+    let n = root.create("n").unwrap();
+    n.write_at(0, b"new data");
+    assert!(!f.is_stale());
+    assert_eq!(f.read_all(), b"old");
+    drop(f);
+    assert_eq!(efs.lock().reap(), 1);
+    assert_eq!(root.find("n").unwrap().read_all(), b"new data");
+}
+
+#[test]
+fn handle_to_moved_inode_goes_stale() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("a").unwrap();
+    root.create("b").unwrap().write_at(0, b"bee");
+    let b = root.find("b").unwrap();
+    root.unlink("a").unwrap();
+    // b 移到 a 空出的编号，原来的编号重新分配后旧句柄过期
+    assert_eq!(efs.lock().compact_inodes(), Ok(1));
+    let n = root.create("n").unwrap();
+    n.write_at(0, b"new data");
+    assert!(b.is_stale());
+    let mut buf = [0u8; 8];
+    assert_eq!(b.read_at(0, &mut buf), 0);
+    assert_eq!(b.try_write_at(0, b"x"), Err(EfsError::Stale));
+    b.clear();
+    // 过期句柄的操作不能影响新文件
+    assert_eq!(root.find("n").unwrap().read_at(0, &mut buf), 8);
+    assert_eq!(&buf, b"new data");
+    assert!(!n.is_stale());
+    assert_eq!(root.find("b").unwrap().read_all(), b"bee");
+}
+
+#[test]
+fn generation_survives_remount() {
+    let dev = MemDev::new(4096);
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("a").unwrap();
+    root.create("b").unwrap();
+    drop(root);
+    drop(efs);
+    let efs = EasyFileSystem::open(dev);
+    let root = EasyFileSystem::root_inode(&efs);
+    let b = root.find("b").unwrap();
+    assert!(!b.is_stale());
+    root.unlink("a").unwrap();
+    assert_eq!(efs.lock().compact_inodes(), Ok(1));
+    root.create("n").unwrap();
+    assert!(b.is_stale());
+}
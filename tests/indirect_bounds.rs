@@ -0,0 +1,77 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem, EfsError,
+    Inode, BLOCK_SZ,
+};
+use std::sync::Arc;
+
+const FILE_SZ: usize = 40 * BLOCK_SZ;
+
+// 一个用到索引块的文件，每个索引块的指针都改成 pointer，
+// 最后一个字可能是块类型标签，保留不变
+fn file_with_index_pointer(pointer: u32) -> (Arc<spin::Mutex<EasyFileSystem>>, Arc<Inode>) {
+    let dev = MemDev::new(4096);
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[5; FILE_SZ]).unwrap();
+    let index_blocks = f.index_block_ids();
+    block_cache_try_sync_all().unwrap();
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    invalidate_device_cache(&block_device);
+    let mut data = dev.data.lock().unwrap();
+    for index_block in index_blocks {
+        let block = &mut data[index_block as usize * BLOCK_SZ..][..BLOCK_SZ - 4];
+        for word in block.chunks_exact_mut(4) {
+            word.copy_from_slice(&pointer.to_le_bytes());
+        }
+    }
+    (efs, f)
+}
+
+#[test]
+fn pointer_past_device_is_corrupt() {
+    let (efs, f) = file_with_index_pointer(5000);
+    let mut buf = vec![0; FILE_SZ];
+    // 直接块之内的数据不经过索引块
+    assert_eq!(f.try_read_at(0, &mut buf[..BLOCK_SZ]), Ok(BLOCK_SZ));
+    assert_eq!(f.try_read_at(0, &mut buf), Err(EfsError::Corrupt));
+    // 写入在分配任何块之前就失败
+    let free_blocks = efs.lock().free_data_blocks();
+    assert_eq!(f.try_write_at(0, &[6; FILE_SZ]), Err(EfsError::Corrupt));
+    assert_eq!(
+        f.try_write_at_direct(0, &[6; FILE_SZ]),
+        Err(EfsError::Corrupt)
+    );
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks);
+}
+
+#[test]
+fn pointer_into_metadata_is_corrupt() {
+    let (_efs, f) = file_with_index_pointer(1);
+    let mut buf = vec![0; FILE_SZ];
+    assert_eq!(f.try_read_at(0, &mut buf), Err(EfsError::Corrupt));
+    assert_eq!(f.try_write_at(0, &[6; FILE_SZ]), Err(EfsError::Corrupt));
+}
+
+#[cfg(not(feature = "strict-asserts"))]
+#[test]
+fn scatter_and_direct_reads_stop_at_corrupt_pointer() {
+    let (_efs, f) = file_with_index_pointer(5000);
+    let mut buf = vec![0; FILE_SZ];
+    let (head, tail) = buf.split_at_mut(BLOCK_SZ);
+    assert_eq!(f.read_at_scatter(0, &mut [head, tail]), 0);
+    assert_eq!(f.read_at_direct(0, &mut buf), 0);
+    assert_eq!(f.write_at_direct(0, &[6; FILE_SZ]), 0);
+}
+
+#[cfg(feature = "strict-asserts")]
+#[test]
+#[should_panic(expected = "Corrupt on-disk data")]
+fn direct_read_panics_on_corrupt_pointer_when_strict() {
+    let (_efs, f) = file_with_index_pointer(5000);
+    let mut buf = vec![0; FILE_SZ];
+    f.read_at_direct(0, &mut buf);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{invalidate_device_cache, BlockDevice, EasyFileSystem};
+use std::sync::Arc;
+
+#[test]
+fn small_files_use_no_data_blocks() {
+    let efs = EasyFileSystem::create(MemDev::new(8192), 8192, 1);
+    efs.lock().set_inline_data(true);
+    let root = EasyFileSystem::root_inode(&efs);
+    let free0 = efs.lock().free_data_blocks();
+    let f = root.create("tiny").unwrap();
+    f.write_at(0, b"hello inline");
+    assert_eq!(efs.lock().free_data_blocks(), free0);
+    assert!(f.data_block_ids().is_empty());
+    let mut buf = [0u8; 12];
+    assert_eq!(f.read_at(0, &mut buf), 12);
+    assert_eq!(&buf, b"hello inline");
+    // 超出内联容量后转为普通的块映射，原有内容保留
+    f.write_at(12, &[7u8; 200]);
+    assert_eq!(efs.lock().free_data_blocks(), free0 - 1);
+    let mut buf = [0u8; 212];
+    assert_eq!(f.read_at(0, &mut buf), 212);
+    assert_eq!(&buf[..12], b"hello inline");
+    assert!(buf[12..].iter().all(|&byte| byte == 7));
+}
+
+#[test]
+fn cleared_inline_file_reads_zeros_before_new_data() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    efs.lock().set_inline_data(true);
+    let root = EasyFileSystem::root_inode(&efs);
+    let g = root.create("g").unwrap();
+    g.write_at(0, b"abcdef");
+    g.clear();
+    g.write_at(3, b"x");
+    let mut buf = [9u8; 4];
+    assert_eq!(g.read_at(0, &mut buf), 4);
+    assert_eq!(&buf, b"\0\0\0x");
+}
+
+#[test]
+fn inline_data_survives_remount() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev;
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    efs.lock().set_inline_data(true);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("f").unwrap().write_at(0, b"kept");
+    drop(root);
+    drop(efs);
+    invalidate_device_cache(&block_device);
+    let efs = EasyFileSystem::open(block_device);
+    assert!(efs.lock().is_inline_data());
+    let f = EasyFileSystem::root_inode(&efs).find("f").unwrap();
+    assert_eq!(f.read_all(), b"kept");
+    assert!(efs.lock().check_inode(1).is_empty());
+}
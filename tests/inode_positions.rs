@@ -0,0 +1,71 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem, EfsError,
+    BLOCK_SZ,
+};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[test]
+fn inode_positions_follow_inode_size() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let fs = efs.lock();
+    let info = fs.layout_info();
+    let (per_block, size) = (info.inodes_per_block, info.inode_size as usize);
+    assert_eq!(per_block as usize, BLOCK_SZ / size);
+    let inode_area = info.inode_start_block..info.inode_start_block + info.inode_area_blocks;
+    let mut seen = HashSet::new();
+    for inode_id in 0..info.inode_bitmap_blocks * BLOCK_SZ as u32 * 8 {
+        let (block_id, offset) = fs.get_disk_inode_pos(inode_id);
+        assert!(inode_area.contains(&block_id));
+        assert!(offset % size == 0 && offset + size <= BLOCK_SZ);
+        // 每个 inode 占据块内不同的槽位，不会重叠
+        assert!(seen.insert((block_id, offset / size)));
+        assert_eq!(fs.get_inode_id(block_id, offset), inode_id);
+    }
+}
+
+#[test]
+fn neighbouring_inodes_keep_their_own_fields() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let count = 3 * efs.lock().layout_info().inodes_per_block as usize;
+    for i in 0..count {
+        root.create(&format!("f{}", i))
+            .unwrap()
+            .write_all(&vec![i as u8; i * 7])
+            .unwrap();
+    }
+    drop(root);
+    drop(efs);
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    let efs = EasyFileSystem::open(block_device);
+    let root = EasyFileSystem::root_inode(&efs);
+    for i in 0..count {
+        let f = root.find(&format!("f{}", i)).unwrap();
+        assert_eq!(f.read_all(), vec![i as u8; i * 7]);
+    }
+}
+
+#[test]
+fn open_refuses_inode_area_too_small_for_inode_size() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let info = EasyFileSystem::create(block_device.clone(), 4096, 1)
+        .lock()
+        .layout_info();
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    // 把超级块中 inode 区的块数减一，装不下位图中的所有 inode
+    let inode_area_blocks = info.inode_area_blocks - 1;
+    dev.data.lock().unwrap()[12..16].copy_from_slice(&inode_area_blocks.to_le_bytes());
+    assert_eq!(
+        EasyFileSystem::try_open(block_device).err(),
+        Some(EfsError::InvalidSuperBlock)
+    );
+}
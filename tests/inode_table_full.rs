@@ -0,0 +1,42 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{DiskInodeType, EasyFileSystem, EfsError};
+
+#[test]
+fn create_fails_cleanly_when_inodes_run_out() {
+    let efs = EasyFileSystem::create(MemDev::new(8192), 8192, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    // 分散到多个子目录中，目录大小不受 small-fanout 的文件大小上限限制
+    let mut dirs = Vec::new();
+    while efs.lock().free_inodes() > 0 {
+        let d = root.mkdir(&format!("d{}", dirs.len())).unwrap();
+        let count = efs.lock().free_inodes().min(1000);
+        let names: Vec<String> = (0..count).map(|i| format!("f{}", i)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        assert!(d.create_batch(&names).iter().all(Option::is_some));
+        dirs.push(d);
+    }
+    let d = dirs.last().unwrap();
+    let entries = d.ls();
+    let size = d.len();
+    let free_blocks = efs.lock().free_data_blocks();
+    let epoch = efs.lock().epoch();
+    for type_ in [DiskInodeType::File, DiskInodeType::Directory] {
+        assert_eq!(
+            d.try_create_inode("x", type_).err(),
+            Some(EfsError::NoFreeInode)
+        );
+    }
+    assert!(d.create_unchecked("y").is_none());
+    assert!(d.create_batch(&["z"])[0].is_none());
+    // 目录没有改动，也没有占用数据块
+    assert_eq!(d.ls(), entries);
+    assert_eq!(d.len(), size);
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks);
+    assert_eq!(efs.lock().epoch(), epoch);
+    assert!(d.check_dir().is_ok());
+    // 释放一个 inode 之后又能创建
+    d.unlink("f0").unwrap();
+    assert!(d.create("x").is_some());
+}
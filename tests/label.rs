@@ -0,0 +1,36 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{invalidate_device_cache, BlockDevice, EasyFileSystem, LABEL_LEN};
+use std::sync::Arc;
+
+#[test]
+fn label_and_uuid_persist() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev;
+    let mut label = [0u8; LABEL_LEN];
+    label[..5].copy_from_slice(b"disk0");
+    let uuid = *b"0123456789abcdef";
+    let efs = EasyFileSystem::create_with_label(block_device.clone(), 4096, 1, label, uuid);
+    assert_eq!(efs.lock().label(), label);
+    drop(efs);
+    invalidate_device_cache(&block_device);
+    let efs = EasyFileSystem::open(block_device.clone());
+    assert_eq!(efs.lock().label(), label);
+    assert_eq!(efs.lock().uuid(), uuid);
+    // 改名只影响标签，UUID 不变
+    let new = [b'x'; LABEL_LEN];
+    efs.lock().set_label(new);
+    drop(efs);
+    invalidate_device_cache(&block_device);
+    let efs = EasyFileSystem::open(block_device);
+    assert_eq!(efs.lock().label(), new);
+    assert_eq!(efs.lock().uuid(), uuid);
+}
+
+#[test]
+fn plain_create_leaves_label_and_uuid_empty() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    assert_eq!(efs.lock().label(), [0; LABEL_LEN]);
+    assert_eq!(efs.lock().uuid(), [0; 16]);
+}
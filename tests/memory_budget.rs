@@ -0,0 +1,38 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockCacheManager, BlockDevice, BLOCK_SZ};
+use std::sync::Arc;
+
+#[test]
+fn memory_usage_scales_with_residency() {
+    let dev: Arc<dyn BlockDevice> = MemDev::new(64);
+    let mut manager = BlockCacheManager::new();
+    assert_eq!(manager.memory_usage(), 0);
+    manager.get_block_cache(0, Arc::clone(&dev));
+    let one = manager.memory_usage();
+    assert!(one > BLOCK_SZ);
+    for block_id in 1..10 {
+        manager.get_block_cache(block_id, Arc::clone(&dev));
+    }
+    assert_eq!(manager.memory_usage(), 10 * one);
+}
+
+#[test]
+fn memory_budget_bounds_resident_buffers() {
+    let dev: Arc<dyn BlockDevice> = MemDev::new(256);
+    let mut manager = BlockCacheManager::new();
+    let budget = 64 * 1024;
+    manager.set_memory_budget(budget);
+    // 每个缓存至少占一个块的缓冲区
+    assert!(manager.capacity() > 0);
+    assert!(manager.capacity() * BLOCK_SZ <= budget);
+    for block_id in 0..256 {
+        manager.get_block_cache(block_id, Arc::clone(&dev));
+    }
+    assert!(manager.memory_usage() <= budget);
+    assert!(manager.memory_usage() + manager.memory_usage() / manager.capacity() > budget);
+    manager.set_memory_budget(0);
+    assert_eq!(manager.capacity(), 1);
+    assert!(manager.memory_usage() <= BLOCK_SZ * 2);
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{invalidate_device_cache, BlockDevice, EasyFileSystem};
+use std::sync::Arc;
+
+#[test]
+fn mkdir_creates_dot_entries() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    let names: Vec<_> = d
+        .ls_range(0, 10)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, [".", ".."]);
+    let e = d.mkdir("e").unwrap();
+    e.create("x").unwrap();
+    // find_path 不会越过起始目录，find 则沿 ".." 向上
+    assert!(e.find_path("../../x").is_some());
+    assert!(e
+        .find("..")
+        .unwrap()
+        .find("..")
+        .unwrap()
+        .find("d")
+        .is_some());
+    assert!(d.find("..").unwrap().find("d").is_some());
+    assert!(root.find_path("d/e/./x").is_some());
+    assert!(root.find_path("d/./e/../e").is_some());
+    // "." 指向自身，".." 指向上一级目录
+    let d_id = root.ls_range(2, 1)[0].1;
+    assert_eq!(d.ls_range(0, 1)[0].1, d_id);
+    assert_eq!(e.ls_range(1, 1)[0].1, d_id);
+    assert!(!root.rename(".", "z"));
+    assert_eq!((root.nlink(), d.nlink(), e.nlink()), (3, 3, 2));
+}
+
+#[test]
+fn dot_entries_reach_the_disk() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev;
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    EasyFileSystem::root_inode(&efs).mkdir("d").unwrap();
+    drop(efs);
+    // 丢弃缓存，只从磁盘上的内容重新挂载
+    invalidate_device_cache(&block_device);
+    let efs = EasyFileSystem::open(block_device);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.find("d").unwrap();
+    assert_eq!(d.ls(), [".", ".."]);
+    assert_eq!((root.nlink(), d.nlink()), (3, 2));
+    assert!(efs.lock().check_inode(1).is_empty());
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{DiskInodeType, EasyFileSystem, EfsError};
+
+const LONGEST: &str = "abcdefghijklmnopqrstuvwxyz0";
+const TOO_LONG: &str = "abcdefghijklmnopqrstuvwxyz01";
+
+#[test]
+fn create_batch_skips_invalid_names() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let free_inodes = efs.lock().free_inodes();
+    let names = ["a", TOO_LONG, "", "x/y", "..", "nul\0", LONGEST, "a"];
+    let created: Vec<bool> = root
+        .create_batch(&names)
+        .iter()
+        .map(Option::is_some)
+        .collect();
+    assert_eq!(
+        created,
+        [true, false, false, false, false, false, true, false]
+    );
+    assert_eq!(efs.lock().free_inodes(), free_inodes - 2);
+    assert!(root.find(LONGEST).is_some());
+    assert!(root.check_dir().is_ok());
+}
+
+#[test]
+fn create_unchecked_refuses_invalid_names() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let free_inodes = efs.lock().free_inodes();
+    for name in [TOO_LONG, "", ".", "x/y"] {
+        assert!(root.create_unchecked(name).is_none());
+    }
+    assert_eq!(efs.lock().free_inodes(), free_inodes);
+    assert!(root.create_unchecked(LONGEST).is_some());
+}
+
+#[test]
+fn create_and_rename_refuse_invalid_names() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    assert_eq!(
+        root.try_create_inode(TOO_LONG, DiskInodeType::File).err(),
+        Some(EfsError::InvalidName)
+    );
+    assert!(root.mkdir("x/y").is_none());
+    root.create("f").unwrap();
+    assert!(!root.rename("f", TOO_LONG));
+    assert!(root.find("f").is_some());
+}
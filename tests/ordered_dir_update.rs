@@ -0,0 +1,72 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockDevice, EasyFileSystem};
+use std::sync::{Arc, Mutex};
+
+// 记录写入设备的每个块，用来重放任意前缀模拟写到一半时断电
+struct WriteLog {
+    inner: Arc<MemDev>,
+    log: Mutex<Vec<(usize, Vec<u8>)>>,
+}
+
+impl BlockDevice for WriteLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.log.lock().unwrap().push((block_id, buf.to_vec()));
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+#[test]
+fn every_write_prefix_is_consistent() {
+    let dev = Arc::new(WriteLog {
+        inner: MemDev::new(4096),
+        log: Mutex::new(Vec::new()),
+    });
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    let mut checked = 0;
+    // 目录项逐渐跨过块边界，中间穿插批量创建
+    for n in 0..24 {
+        let snapshot = dev.inner.data.lock().unwrap().clone();
+        dev.log.lock().unwrap().clear();
+        if n % 7 == 3 {
+            let names: Vec<String> = (0..5).map(|i| format!("b{}_{}", n, i)).collect();
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            d.create_batch(&names);
+        } else {
+            d.create(&format!("f{}", n)).unwrap();
+        }
+        let log = dev.log.lock().unwrap().clone();
+        let entries = d.ls().len();
+        for k in 0..=log.len() {
+            let image = MemDev::new(4096);
+            *image.data.lock().unwrap() = snapshot.clone();
+            for (block_id, buf) in &log[..k] {
+                image.write_block(*block_id, buf);
+            }
+            let efs = EasyFileSystem::open(image);
+            let d = EasyFileSystem::root_inode(&efs).find("d").unwrap();
+            let names = d.ls();
+            assert!(names.len() <= entries);
+            for name in names.iter().filter(|name| *name != "." && *name != "..") {
+                assert!(
+                    name.starts_with('f') || name.starts_with('b'),
+                    "{} {} {:?}",
+                    n,
+                    k,
+                    name
+                );
+                assert!(d.find(name).is_some(), "{} {} {}", n, k, name);
+            }
+            // 目录项不能指向分配还没有写到磁盘的 inode
+            assert!(efs.lock().check_inode(1).is_empty(), "{} {}", n, k);
+            checked += 1;
+        }
+    }
+    assert!(checked > 24);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{EasyFileSystem, EfsError, BLOCK_SZ};
+
+#[test]
+fn punch_hole_releases_whole_blocks() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[7; 8 * BLOCK_SZ]).unwrap();
+    let free_blocks = efs.lock().free_data_blocks();
+    // 首尾不完整的块只清零
+    assert_eq!(
+        f.punch_hole(BLOCK_SZ as u64 + 100, 3 * BLOCK_SZ as u64),
+        Ok(())
+    );
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks + 2);
+    assert_eq!(f.len(), 8 * BLOCK_SZ as u64);
+    let data = f.read_all();
+    assert!(data[..BLOCK_SZ + 100].iter().all(|&b| b == 7));
+    assert!(data[BLOCK_SZ + 100..4 * BLOCK_SZ + 100]
+        .iter()
+        .all(|&b| b == 0));
+    assert!(data[4 * BLOCK_SZ + 100..].iter().all(|&b| b == 7));
+}
+
+#[test]
+fn punch_hole_refuses_dirs_and_immutable_files() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    assert_eq!(d.punch_hole(0, BLOCK_SZ as u64), Err(EfsError::IsDir));
+    let f = root.create("f").unwrap();
+    f.write_all(&[7; 2 * BLOCK_SZ]).unwrap();
+    f.set_immutable(true);
+    assert_eq!(f.punch_hole(0, BLOCK_SZ as u64), Err(EfsError::Immutable));
+    assert_eq!(f.read_all(), [7; 2 * BLOCK_SZ]);
+}
+
+#[test]
+fn punch_hole_through_stale_handle_fails() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("a").unwrap();
+    let f = root.create("f").unwrap();
+    f.write_all(&[7; 2 * BLOCK_SZ]).unwrap();
+    root.unlink("a").unwrap();
+    assert_eq!(efs.lock().compact_inodes(), Ok(1));
+    root.create("n")
+        .unwrap()
+        .write_all(&[8; 2 * BLOCK_SZ])
+        .unwrap();
+    assert_eq!(f.punch_hole(0, BLOCK_SZ as u64), Err(EfsError::Stale));
+    assert_eq!(root.find("n").unwrap().read_all(), [8; 2 * BLOCK_SZ]);
+}
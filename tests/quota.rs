@@ -0,0 +1,64 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{invalidate_device_cache, BlockDevice, EasyFileSystem, EfsError, Inode};
+use std::sync::Arc;
+
+#[test]
+fn quota_limits_directory_and_children() {
+    let efs = EasyFileSystem::create(MemDev::new(8192), 8192, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    d.set_quota(10);
+    let f = d.create("f").unwrap();
+    // 目录本身占用 1 块，文件写满 8 块后只剩 1 块
+    assert_eq!(f.try_write_at(0, &[1u8; 512 * 8]), Ok(4096));
+    assert_eq!(
+        f.try_write_at(4096, &[1u8; 512 * 2]),
+        Err(EfsError::QuotaExceeded)
+    );
+    assert_eq!(f.try_write_at(4096, &[1u8; 512]), Ok(512));
+    // 限额之外的目录不受影响
+    let g = root.create("g").unwrap();
+    assert_eq!(g.try_write_at(0, &[1u8; 5000]), Ok(5000));
+}
+
+#[test]
+fn quota_applies_to_parentless_handles() {
+    let dev = MemDev::new(8192);
+    let block_device: Arc<dyn BlockDevice> = dev;
+    let efs = EasyFileSystem::create(block_device.clone(), 8192, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let d = root.mkdir("d").unwrap();
+    d.set_quota(4);
+    d.create("f").unwrap();
+    let inode_id = d
+        .ls_range(0, 10)
+        .into_iter()
+        .find(|(name, _)| name == "f")
+        .unwrap()
+        .1;
+    let (block_id, block_offset) = efs.lock().get_disk_inode_pos(inode_id);
+    let f = Inode::new(block_id, block_offset, efs.clone(), block_device);
+    assert_eq!(
+        f.try_write_at(0, &[1u8; 512 * 4]),
+        Err(EfsError::QuotaExceeded)
+    );
+    assert_eq!(f.try_write_at(0, &[1u8; 512 * 3]), Ok(512 * 3));
+}
+
+#[test]
+fn open_rejects_images_without_inode_v2() {
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    drop(EasyFileSystem::create(block_device.clone(), 4096, 1));
+    invalidate_device_cache(&block_device);
+    assert!(EasyFileSystem::try_open(block_device.clone()).is_ok());
+    // 清除超级块中 flags 字段的 SB_FLAG_INODE_V2，模拟旧格式的镜像
+    dev.data.lock().unwrap()[28] &= !32;
+    invalidate_device_cache(&block_device);
+    assert_eq!(
+        EasyFileSystem::try_open(block_device).err(),
+        Some(EfsError::InvalidSuperBlock)
+    );
+}
@@ -0,0 +1,42 @@
+// 同一线程再次给块缓存加锁的检查只在带 std 的调试构建中进行
+#![cfg(all(feature = "std", debug_assertions))]
+
+mod common;
+
+use common::MemDev;
+use easy_fs::{BlockDevice, BLOCK_CACHE_MANAGER};
+use std::sync::Arc;
+
+#[test]
+fn other_block_can_be_locked_inside_read() {
+    let block_device: Arc<dyn BlockDevice> = MemDev::new(16);
+    let cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(0, block_device.clone());
+    cache.lock().read(0, |_: &[u8; 16]| {
+        BLOCK_CACHE_MANAGER
+            .lock()
+            .get_block_cache(1, block_device.clone())
+            .lock()
+            .modify(0, |data: &mut u8| *data = 1);
+    });
+    // 闭包返回之后可以再次获取同一个块
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(0, block_device.clone());
+}
+
+#[test]
+#[should_panic(expected = "already locked by this thread")]
+fn relocking_the_same_block_inside_read_panics() {
+    let block_device: Arc<dyn BlockDevice> = MemDev::new(16);
+    let cache = BLOCK_CACHE_MANAGER
+        .lock()
+        .get_block_cache(0, block_device.clone());
+    cache.lock().read(0, |_: &[u8; 16]| {
+        // 不检查的话会在自旋锁上永远等待
+        BLOCK_CACHE_MANAGER
+            .lock()
+            .get_block_cache(0, block_device.clone());
+    });
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{set_trace_hook, EasyFileSystem, TraceEvent};
+use std::sync::Mutex;
+
+// 跟踪钩子是全局的，本文件的测试依次运行
+static SERIAL: Mutex<()> = Mutex::new(());
+static EVENTS: Mutex<Vec<TraceEvent>> = Mutex::new(Vec::new());
+
+fn record(event: &TraceEvent) {
+    EVENTS.lock().unwrap().push(*event);
+}
+
+fn unlink_events() -> usize {
+    EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| matches!(event, TraceEvent::Unlink { .. }))
+        .count()
+}
+
+#[test]
+fn rename_over_releases_closed_target() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("src").unwrap().write_all(&[1; 1500]).unwrap();
+    let (free_inodes, free_blocks) = {
+        let fs = efs.lock();
+        (fs.free_inodes(), fs.free_data_blocks())
+    };
+    root.create("dst").unwrap().write_all(&[2; 3000]).unwrap();
+    assert!(root.rename("src", "dst"));
+    let fs = efs.lock();
+    assert_eq!(fs.free_inodes(), free_inodes);
+    assert_eq!(fs.free_data_blocks(), free_blocks);
+    drop(fs);
+    assert_eq!(root.find("dst").unwrap().read_all(), [1; 1500]);
+}
+
+#[test]
+fn rename_over_defers_open_target() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("src").unwrap().write_all(&[1; 1500]).unwrap();
+    let (free_inodes, free_blocks) = {
+        let fs = efs.lock();
+        (fs.free_inodes(), fs.free_data_blocks())
+    };
+    let dst = root.create("dst").unwrap();
+    dst.write_all(&[2; 3000]).unwrap();
+    assert!(root.rename("src", "dst"));
+    // 打开的旧目标还能读写
+    assert_eq!(dst.nlink(), 0);
+    assert_eq!(dst.write_at(3000, &[3; 100]), 100);
+    assert_eq!(dst.len(), 3100);
+    assert!(efs.lock().free_data_blocks() < free_blocks);
+    drop(dst);
+    assert_eq!(efs.lock().reap(), 1);
+    let fs = efs.lock();
+    assert_eq!(fs.free_inodes(), free_inodes);
+    assert_eq!(fs.free_data_blocks(), free_blocks);
+}
+
+#[test]
+fn rename_traces_unlink_only_when_target_removed() {
+    let _serial = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    root.create("a").unwrap();
+    root.create("b").unwrap();
+    root.mkdir("d").unwrap();
+    EVENTS.lock().unwrap().clear();
+    set_trace_hook(Some(record));
+    // 目标是目录时重命名失败，不报告删除
+    assert!(!root.rename("a", "d"));
+    let refused = unlink_events();
+    assert!(root.rename("a", "c"));
+    let plain = unlink_events();
+    assert!(root.rename("c", "b"));
+    let over = unlink_events();
+    set_trace_hook(None);
+    assert_eq!((refused, plain, over), (0, 0, 1));
+}
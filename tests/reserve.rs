@@ -0,0 +1,61 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{EasyFileSystem, EfsError};
+
+#[test]
+fn reserved_blocks_are_used_by_later_writes() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("a").unwrap();
+    f.write_at(0, b"hi");
+    let free0 = efs.lock().free_data_blocks();
+    f.reserve(60 * 512).unwrap();
+    let free1 = efs.lock().free_data_blocks();
+    let data_blocks = f.data_block_ids().len();
+    assert_eq!(data_blocks, 60);
+    assert_eq!(free0 - free1, 59 + f.index_block_ids().len());
+    // 预留不改变文件大小
+    assert_eq!(f.len(), 2);
+    let mut buf = [7u8; 4];
+    assert_eq!(f.read_at(0, &mut buf), 2);
+    f.write_at(0, &[1u8; 60 * 512]);
+    assert_eq!(efs.lock().free_data_blocks(), free1);
+    assert_eq!(f.data_block_ids().len(), data_blocks);
+    let index_blocks = f.index_block_ids().len();
+    f.write_at(60 * 512, &[1u8]);
+    let grown = 1 + f.index_block_ids().len() - index_blocks;
+    assert_eq!(efs.lock().free_data_blocks(), free1 - grown);
+}
+
+#[test]
+fn failed_reserve_allocates_nothing() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("a").unwrap();
+    let free = efs.lock().free_data_blocks();
+    assert_eq!(f.reserve(1 << 40), Err(EfsError::NoSpace));
+    assert_eq!(f.reserve(4000 * 512), Err(EfsError::NoSpace));
+    assert_eq!(efs.lock().free_data_blocks(), free);
+    assert!(f.data_block_ids().is_empty());
+}
+
+#[test]
+fn reserved_range_reads_as_zeros() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let g = root.create("g").unwrap();
+    g.reserve(10_000).unwrap();
+    let mut buf = vec![9u8; 10];
+    assert_eq!(g.read_at(0, &mut buf), 0);
+    g.write_at(5000, b"x");
+    let mut buf = vec![9u8; 5001];
+    assert_eq!(g.read_at(0, &mut buf), 5001);
+    assert!(buf[..5000].iter().all(|&byte| byte == 0));
+    // 清空时预留的块一并释放
+    let before = efs.lock().free_data_blocks();
+    let blocks = g.data_block_ids().len() + g.index_block_ids().len();
+    assert_eq!(g.data_block_ids().len(), 20);
+    g.clear();
+    assert_eq!(efs.lock().free_data_blocks(), before + blocks);
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem,
+    BLOCK_CACHE_MANAGER, BLOCK_SZ,
+};
+use std::sync::{Arc, Mutex};
+
+// 跳过未改变写入的开关是全局的，各测试依次打开
+static SKIP_UNCHANGED: Mutex<()> = Mutex::new(());
+
+// 写入全 0 的整块，块在缓存中不驻留时也必须写到磁盘上
+#[test]
+fn zero_write_over_evicted_blocks_reaches_disk() {
+    let _guard = SKIP_UNCHANGED.lock().unwrap_or_else(|err| err.into_inner());
+    let dev = MemDev::new(4096);
+    let block_device: Arc<dyn BlockDevice> = dev;
+    let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    assert_eq!(f.write_at(0, &[1u8; 2 * BLOCK_SZ]), 2 * BLOCK_SZ);
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    BLOCK_CACHE_MANAGER.lock().set_skip_unchanged_writes(true);
+    assert_eq!(f.write_at(0, &[0u8; 2 * BLOCK_SZ]), 2 * BLOCK_SZ);
+    block_cache_try_sync_all().unwrap();
+    invalidate_device_cache(&block_device);
+    BLOCK_CACHE_MANAGER.lock().set_skip_unchanged_writes(false);
+    let mut buf = [9u8; 2 * BLOCK_SZ];
+    assert_eq!(f.read_at(0, &mut buf), 2 * BLOCK_SZ);
+    assert!(buf.iter().all(|&byte| byte == 0));
+}
+
+// 不超过一个块的文件走单块的快速路径
+#[test]
+fn zero_write_over_single_block_file_reaches_disk() {
+    let _guard = SKIP_UNCHANGED.lock().unwrap_or_else(|err| err.into_inner());
+    for len in [100, BLOCK_SZ] {
+        let dev = MemDev::new(4096);
+        let block_device: Arc<dyn BlockDevice> = dev;
+        let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+        let f = EasyFileSystem::root_inode(&efs).create("f").unwrap();
+        assert_eq!(f.write_at(0, &vec![1u8; len]), len);
+        block_cache_try_sync_all().unwrap();
+        invalidate_device_cache(&block_device);
+        BLOCK_CACHE_MANAGER.lock().set_skip_unchanged_writes(true);
+        assert_eq!(f.write_at(0, &vec![0u8; len]), len);
+        block_cache_try_sync_all().unwrap();
+        invalidate_device_cache(&block_device);
+        BLOCK_CACHE_MANAGER.lock().set_skip_unchanged_writes(false);
+        let mut buf = vec![9u8; len];
+        assert_eq!(f.read_at(0, &mut buf), len);
+        assert!(buf.iter().all(|&byte| byte == 0), "{}", len);
+    }
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, checkpoint, flush_dirty_sorted, BlockDevice, EasyFileSystem, Issue,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// 在另一个线程中运行 f，超时视为死锁
+fn run_with_timeout(secs: u64, f: impl FnOnce() + Send + 'static) {
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        f();
+        tx.send(()).unwrap();
+    });
+    if rx.recv_timeout(Duration::from_secs(secs)).is_err() {
+        // 线程 panic 时 join 报告原来的错误
+        if handle.is_finished() {
+            handle.join().unwrap();
+        }
+        panic!("deadlock: not finished within {} seconds", secs);
+    }
+    handle.join().unwrap();
+}
+
+// 检查所有已分配的 inode，不应有任何问题
+fn check_all_inodes(efs: &Arc<spin::Mutex<EasyFileSystem>>) {
+    let fs = efs.lock();
+    let layout = fs.layout_info();
+    for inode_id in 0..layout.inode_area_blocks * layout.inodes_per_block {
+        let issues = fs.check_inode(inode_id);
+        assert!(
+            issues.is_empty() || issues == [Issue::InodeNotAllocated],
+            "inode {}: {:?}",
+            inode_id,
+            issues
+        );
+    }
+}
+
+#[test]
+fn concurrent_directory_operations() {
+    run_with_timeout(60, || {
+        let dev = MemDev::new(8192);
+        let efs = EasyFileSystem::create(dev, 8192, 1);
+        let root = Arc::new(EasyFileSystem::root_inode(&efs));
+        let shared = root.mkdir("shared").unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let root = Arc::clone(&root);
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    let own = root.mkdir(&format!("d{}", t)).unwrap();
+                    for i in 0..15 {
+                        let name = format!("t{}_{}", t, i);
+                        let data = vec![(t * 31 + i) as u8; 1000 + i * 150];
+                        for dir in [&own, &shared] {
+                            let f = dir.create(&name).unwrap();
+                            f.write_all(&data).unwrap();
+                            assert_eq!(dir.find(&name).unwrap().read_all(), data);
+                        }
+                        root.ls();
+                        if i % 3 == 0 {
+                            assert!(shared.rename(&name, &format!("r{}_{}", t, i)));
+                        }
+                        if i % 5 == 0 {
+                            own.unlink(&name).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(shared.ls().len(), 2 + 4 * 15);
+        for t in 0..4 {
+            assert_eq!(
+                root.find(&format!("d{}", t)).unwrap().ls().len(),
+                2 + 15 - 3
+            );
+        }
+        check_all_inodes(&efs);
+    });
+}
+
+#[test]
+fn io_racing_with_flushes() {
+    run_with_timeout(60, || {
+        let dev = MemDev::new(4096);
+        let block_device: Arc<dyn BlockDevice> = dev.clone();
+        let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+        let root = EasyFileSystem::root_inode(&efs);
+        let f = root.create("f").unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let flusher = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    block_cache_try_sync_all().unwrap();
+                    flush_dirty_sorted();
+                    checkpoint(&block_device, &[0, 1, 2, 3]);
+                    thread::yield_now();
+                }
+            })
+        };
+        let writers: Vec<_> = (0..3)
+            .map(|t| {
+                let f = Arc::clone(&f);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let offset = ((i * 7 + t) % 90) * 512;
+                        assert_eq!(f.write_at(offset, &[t as u8; 700]), 700);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        flusher.join().unwrap();
+        check_all_inodes(&efs);
+    });
+}
+
+#[test]
+fn handles_dropped_while_unlinking() {
+    run_with_timeout(60, || {
+        let dev = MemDev::new(4096);
+        let efs = EasyFileSystem::create(dev, 4096, 1);
+        let root = Arc::new(EasyFileSystem::root_inode(&efs));
+        let (free_inodes, free_blocks) = {
+            let fs = efs.lock();
+            (fs.free_inodes(), fs.free_data_blocks())
+        };
+        for i in 0..20 {
+            root.create(&format!("f{}", i))
+                .unwrap()
+                .write_all(&[i as u8; 2000])
+                .unwrap();
+        }
+        // 打开的句柄在各线程中创建和关闭，同时另一个线程删除这些文件
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let root = Arc::clone(&root);
+                let opened = Arc::clone(&opened);
+                thread::spawn(move || {
+                    for round in 0..50 {
+                        let name = format!("f{}", round % 20);
+                        if let Some(f) = root.find(&name) {
+                            f.read_all();
+                            opened.lock().unwrap().push(f);
+                        }
+                        if round % 4 == 0 {
+                            opened.lock().unwrap().clear();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let unlinker = {
+            let root = Arc::clone(&root);
+            thread::spawn(move || {
+                for i in 0..20 {
+                    root.unlink(&format!("f{}", i)).unwrap();
+                }
+            })
+        };
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        unlinker.join().unwrap();
+        opened.lock().unwrap().clear();
+        efs.lock().reap();
+        let fs = efs.lock();
+        assert_eq!(fs.free_inodes(), free_inodes);
+        assert_eq!(fs.free_data_blocks(), free_blocks);
+    });
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockDevice, EasyFileSystem, EfsError,
+    Inode, BLOCK_SZ,
+};
+use std::sync::Arc;
+
+// 一个用到索引块的文件，每个索引块的第一个指针都指向数据区之外
+fn corrupt_file() -> Arc<Inode> {
+    let dev = MemDev::new(4096);
+    let efs = EasyFileSystem::create(dev.clone(), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let f = root.create("f").unwrap();
+    f.write_all(&[5; 40 * BLOCK_SZ]).unwrap();
+    let index_blocks = f.index_block_ids();
+    block_cache_try_sync_all().unwrap();
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    invalidate_device_cache(&block_device);
+    let mut data = dev.data.lock().unwrap();
+    for index_block in index_blocks {
+        let pos = index_block as usize * BLOCK_SZ;
+        data[pos..pos + 4].copy_from_slice(&5000u32.to_le_bytes());
+    }
+    f
+}
+
+#[test]
+fn try_read_reports_corrupt_pointer_in_both_modes() {
+    let f = corrupt_file();
+    let mut buf = vec![0; 40 * BLOCK_SZ];
+    assert_eq!(f.try_read_at(0, &mut buf), Err(EfsError::Corrupt));
+}
+
+#[cfg(feature = "strict-asserts")]
+#[test]
+#[should_panic(expected = "Corrupt on-disk data")]
+fn read_panics_on_corrupt_pointer_when_strict() {
+    let f = corrupt_file();
+    let mut buf = vec![0; 40 * BLOCK_SZ];
+    f.read_at(0, &mut buf);
+}
+
+#[cfg(not(feature = "strict-asserts"))]
+#[test]
+fn read_reports_nothing_on_corrupt_pointer_when_tolerant() {
+    let f = corrupt_file();
+    let mut buf = vec![0; 40 * BLOCK_SZ];
+    assert_eq!(f.read_at(0, &mut buf), 0);
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{block_cache_try_sync_all, EasyFileSystem, SuperBlock, LABEL_LEN};
+
+#[test]
+fn super_block_is_stored_little_endian() {
+    let dev = MemDev::new(4096);
+    let mut label = [0u8; LABEL_LEN];
+    label[..5].copy_from_slice(b"disk0");
+    let uuid = *b"0123456789abcdef";
+    let efs = EasyFileSystem::create_with_label(dev.clone(), 4096, 1, label, uuid);
+    block_cache_try_sync_all().unwrap();
+    let sb = efs.lock().super_block();
+    let bytes = sb.to_le_bytes();
+    let data = dev.data.lock().unwrap();
+    assert_eq!(&data[..bytes.len()], &bytes[..]);
+    assert_eq!(&bytes[0..4], &sb.magic().to_le_bytes());
+    assert_eq!(&bytes[4..8], &4096u32.to_le_bytes());
+    assert_eq!(&bytes[8..12], &1u32.to_le_bytes());
+    assert_eq!(&bytes[48..48 + LABEL_LEN], &label);
+    assert_eq!(&bytes[80..96], &uuid);
+}
+
+#[test]
+fn super_block_round_trip() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let sb = efs.lock().super_block();
+    let copy = SuperBlock::from_le_bytes(&sb.to_le_bytes());
+    assert!(copy.is_valid());
+    assert_eq!(copy.total_blocks, sb.total_blocks);
+    assert_eq!(copy.inode_bitmap_blocks, sb.inode_bitmap_blocks);
+    assert_eq!(copy.inode_area_blocks, sb.inode_area_blocks);
+    assert_eq!(copy.data_bitmap_blocks, sb.data_bitmap_blocks);
+    assert_eq!(copy.data_area_blocks, sb.data_area_blocks);
+    assert_eq!(copy.to_le_bytes(), sb.to_le_bytes());
+    // 损坏魔数后仍能解析，但不再是合法的超级块
+    let mut bytes = sb.to_le_bytes();
+    bytes[0] ^= 1;
+    assert!(!SuperBlock::from_le_bytes(&bytes).is_valid());
+}
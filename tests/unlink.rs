@@ -0,0 +1,71 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{DiskInodeType, EasyFileSystem, EfsError};
+
+#[test]
+fn unlinked_file_is_released_after_last_handle() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let counts = || {
+        let fs = efs.lock();
+        (fs.free_inodes(), fs.free_data_blocks())
+    };
+    let before = counts();
+    let f = root.create("f").unwrap();
+    f.write_at(0, &[1u8; 3000]);
+    let plan = root.unlink("f").unwrap();
+    assert!(plan.inodes.is_empty());
+    assert!(root.find("f").is_none());
+    // 打开的句柄仍然可以读写
+    assert_eq!(f.write_at(3000, &[2u8; 2000]), 2000);
+    assert_eq!(f.read_all().len(), 5000);
+    assert!(!f.is_stale());
+    assert!(counts().1 < before.1);
+    drop(f);
+    assert_eq!(efs.lock().reap(), 1);
+    assert_eq!(counts(), before);
+}
+
+#[test]
+fn remove_all_defers_open_files_and_directories() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let counts = || {
+        let fs = efs.lock();
+        (fs.free_inodes(), fs.free_data_blocks())
+    };
+    let before = counts();
+    let d = root.mkdir("d").unwrap();
+    let f = d.create("f").unwrap();
+    f.write_at(0, &[1u8; 3000]);
+    d.create("g").unwrap().write_at(0, &[1u8; 600]);
+    let plan = root.remove_all("d").unwrap();
+    // 只有没有打开的 g 立即释放
+    assert_eq!(plan.inodes.len(), 1);
+    assert!(root.find("d").is_none());
+    assert_eq!(f.write_at(3000, &[2u8; 2000]), 2000);
+    assert_eq!(f.read_all().len(), 5000);
+    // 已删除的目录不能再添加目录项
+    assert_eq!(
+        d.try_create_inode("h", DiskInodeType::File).err(),
+        Some(EfsError::NotFound)
+    );
+    drop(f);
+    drop(d);
+    assert_eq!(efs.lock().reap(), 2);
+    assert_eq!(counts(), before);
+}
+
+#[test]
+fn orphan_is_released_by_next_allocation() {
+    let efs = EasyFileSystem::create(MemDev::new(4096), 4096, 1);
+    let root = EasyFileSystem::root_inode(&efs);
+    let free = efs.lock().free_inodes();
+    let f = root.create("f").unwrap();
+    root.unlink("f").unwrap();
+    drop(f);
+    root.create("g").unwrap();
+    assert_eq!(efs.lock().free_inodes(), free - 1);
+    assert_eq!(efs.lock().reap(), 0);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::MemDev;
+use easy_fs::{
+    block_cache_try_sync_all, invalidate_device_cache, BlockCacheManager, BlockDevice,
+    EasyFileSystem, BLOCK_CACHE_MANAGER, BLOCK_SZ,
+};
+use std::sync::{Arc, Mutex};
+
+// 记录读过的块号
+struct ReadLog {
+    inner: Arc<MemDev>,
+    reads: Mutex<Vec<usize>>,
+}
+
+impl BlockDevice for ReadLog {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push(block_id);
+        self.inner.read_block(block_id, buf)
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        self.inner.write_block(block_id, buf)
+    }
+}
+
+fn read_log() -> Arc<ReadLog> {
+    Arc::new(ReadLog {
+        inner: MemDev::new(4096),
+        reads: Mutex::new(Vec::new()),
+    })
+}
+
+#[test]
+fn remount_with_warm_cache_reads_nothing() {
+    let dev = read_log();
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+    {
+        let efs = EasyFileSystem::create(block_device.clone(), 4096, 1);
+        let root = EasyFileSystem::root_inode(&efs);
+        root.create("f").unwrap().write_all(&data).unwrap();
+        assert_eq!(root.find("f").unwrap().read_all(), data);
+    }
+    block_cache_try_sync_all().unwrap();
+    let warm = BLOCK_CACHE_MANAGER.lock().export_warm(&block_device);
+    assert!(!warm.is_empty());
+    invalidate_device_cache(&block_device);
+    BLOCK_CACHE_MANAGER.lock().import_warm(&block_device, warm);
+    dev.reads.lock().unwrap().clear();
+    let efs = EasyFileSystem::open(block_device.clone());
+    let root = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root.find("f").unwrap().read_all(), data);
+    assert_eq!(*dev.reads.lock().unwrap(), []);
+}
+
+#[test]
+fn import_keeps_resident_blocks_and_skips_dirty_ones() {
+    let dev = read_log();
+    let block_device: Arc<dyn BlockDevice> = dev.clone();
+    let mut manager = BlockCacheManager::new();
+    for block_id in 0..3 {
+        manager.get_block_cache(block_id, block_device.clone());
+    }
+    manager
+        .get_block_cache(2, block_device.clone())
+        .lock()
+        .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(2));
+    // 修改过的块不导出
+    let warm = manager.export_warm(&block_device);
+    let mut ids: Vec<usize> = warm.iter().map(|&(block_id, _)| block_id).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, [0, 1]);
+    let mut other = BlockCacheManager::new();
+    other
+        .get_block_cache(1, block_device.clone())
+        .lock()
+        .modify(0, |data: &mut [u8; BLOCK_SZ]| data.fill(1));
+    dev.reads.lock().unwrap().clear();
+    other.import_warm(&block_device, warm);
+    // 已经驻留的块保留自己的内容
+    let mut byte = |block_id| {
+        other
+            .get_block_cache(block_id, block_device.clone())
+            .lock()
+            .read(0, |data: &[u8; BLOCK_SZ]| data[0])
+    };
+    assert_eq!(byte(1), 1);
+    assert_eq!(byte(0), 0);
+    assert_eq!(*dev.reads.lock().unwrap(), []);
+}